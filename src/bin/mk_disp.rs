@@ -1,26 +1,94 @@
 use std::path::Path;
-use wordfreak::types::Corpus;
+use wordfreak::types::{Corpus, TokenKey};
 use wordfreak::vocab::VocabMap;
 use argh::FromArgs;
-use std::collections::BTreeMap;
 use itertools::Itertools;
 use superslice::*;
-use wordfreak::parquet2::write_parquet;
-use wordfreak::dispersion::{AccElement, acc_word, reduce_word, norm_word, FinalColumns};
-use wordfreak::corpus::{CorpusType, get_corpus};
+use wordfreak::parquet2::{NonUtf8Words, write_parquet};
+use wordfreak::dispersion::{Metric, MetricsAcc, METRICS, parse_metrics};
+use wordfreak::parallel::{ThreadConfig, set_thread_config};
+use wordfreak::corpus::{CorpusType, CorpusOptions, get_multi_corpus};
+use wordfreak::conllu::{ColumnSelection, DocUnit, MultiwordTokens};
+use wordfreak::vrt::{AttrSelection, VrtDocUnit};
+use wordfreak::error::{Error, ErrorLog, OnError, Result};
 
 
 #[derive(FromArgs)]
 /// add
 struct MkDisp {
-    /// type of corpus to use
+    /// type of corpus to use. Give once for all inputs or once per input.
     #[argh(option)]
-    corpus_type: Option<CorpusType>,
+    corpus_type: Vec<CorpusType>,
 
     /// count lemmas rather than word forms
     #[argh(switch)]
     lemma: bool,
 
+    /// columns of CoNLL-U files to count, e.g. LEMMA or LEMMA+UPOS. Overrides --lemma for
+    /// CoNLL-U.
+    #[argh(option)]
+    columns: Option<ColumnSelection>,
+
+    /// positional attribute of VRT files to count, e.g. lemma or pos. Overrides --lemma for VRT.
+    #[argh(option)]
+    vrt_attr: Option<AttrSelection>,
+
+    /// structural element making up documents in VRT files: text, paragraph, sentence or
+    /// element:<name>. Defaults to text.
+    #[argh(option, default = "VrtDocUnit::Text")]
+    vrt_doc_unit: VrtDocUnit,
+
+    /// element of VRT files to take tokens from. Defaults to sentences or paragraphs, or the
+    /// whole document if it has neither.
+    #[argh(option)]
+    vrt_token_elem: Option<String>,
+
+    /// log and skip malformed lines in VRT files, such as stray tags or bad entities, instead
+    /// of stopping
+    #[argh(switch)]
+    vrt_lenient: bool,
+
+    /// document unit for CoNLL-U files without # newdoc comments: paragraph, sentence or
+    /// window:<size>. Defaults to windows of 1000 tokens.
+    #[argh(option)]
+    fallback_doc_unit: Option<DocUnit>,
+
+    /// whether to count CoNLL-U multiword tokens as their syntactic words or as the surface
+    /// token: words or surface. Defaults to words.
+    #[argh(option, default = "MultiwordTokens::Words")]
+    multiword_tokens: MultiwordTokens,
+
+    /// comma separated metrics to compute out of kl_div, idf, vc, juillands_d, carrols_d, dp,
+    /// dp_norm and zipf. Defaults to all of them.
+    #[argh(option)]
+    metrics: Option<String>,
+
+    /// what to do with archive entries which can't be read: skip or fail. Defaults to fail.
+    #[argh(option, default = "OnError::Fail")]
+    on_error: OnError,
+
+    /// what to do with words which aren't valid UTF-8: replace the invalid bytes, drop the
+    /// words or write the word column as binary. Defaults to replace.
+    #[argh(option, default = "NonUtf8Words::Replace")]
+    non_utf8_words: NonUtf8Words,
+
+    /// where to write a JSON line for each skipped entry with --on-error=skip. Defaults to the
+    /// output path with .errors.jsonl added.
+    #[argh(option)]
+    error_report: Option<String>,
+
+    /// number of threads reading the corpus and extracting tokens
+    #[argh(option, default = "4")]
+    threads: usize,
+
+    /// number of threads decompressing archive entries. Defaults to --threads.
+    #[argh(option)]
+    decompress_threads: Option<usize>,
+
+    /// number of threads merging counts
+    #[argh(option, default = "1")]
+    count_threads: usize,
+
     /// path
     #[argh(positional)]
     output: String,
@@ -32,7 +100,7 @@ struct MkDisp {
 
 /// Indexes the collection and at the same time collects counts per word, as well as the total
 /// token count.
-fn one_scan_index_count(corpus: &Box<dyn Corpus>) -> (VocabMap, Vec<u32>, u32, u32) {
+fn one_scan_index_count(corpus: &Box<dyn Corpus>) -> Result<(VocabMap, Vec<u64>, u64, u64)> {
     /*
     let args: MkDisp = argh::from_env();
     let (sender, receiver) = unbounded();
@@ -54,7 +122,7 @@ fn one_scan_index_count(corpus: &Box<dyn Corpus>) -> (VocabMap, Vec<u32>, u32, u
     let zip_reader: ZipArchive = ZipArchive::new(&mmap).unwrap();
     iter_subtitles(&zip_reader).for_each_init(
         || Vec::<u8>::new(), |mut xml_read_buf, mut reader| {
-            let mut counts: BTreeMap<u32, u32> = BTreeMap::new();
+            let mut counts: BTreeMap<u32, u64> = BTreeMap::new();
             let mut doc_words = 0;
             next_token(&mut xml_read_buf, &mut reader, LEMMA_KEY, |lemma| {
                 let maybe_vocab_idx = vocab.get(lemma);
@@ -70,36 +138,27 @@ fn one_scan_index_count(corpus: &Box<dyn Corpus>) -> (VocabMap, Vec<u32>, u32, u
     pipe_reader.join().unwrap()
     */
     let timer = howlong::ProcessCPUTimer::new();
-    let (vocab_builder, doc_count) = corpus.count_words();
+    let (vocab_builder, doc_count) = corpus.count_words()?;
     let (vocab, word_freqs_indexed, total_words) = vocab_builder.build();
     println!("Gather counts {}", timer.elapsed());
     let timer = howlong::ProcessCPUTimer::new();
     println!("Sort and reindex {}", timer.elapsed());
-    (vocab, word_freqs_indexed, total_words, doc_count)
+    Ok((vocab, word_freqs_indexed, total_words, doc_count))
 }
 
-fn process_corpus(corpus: &Box<dyn Corpus>, output: &str) {
-    let (vocab, word_counts, total_words, num_docs) = one_scan_index_count(corpus);
+fn process_corpus(corpus: &Box<dyn Corpus>, output: &str, metrics: &[&'static Metric], non_utf8: NonUtf8Words) -> Result<()> {
+    let (vocab, word_counts, total_words, num_docs) = one_scan_index_count(corpus)?;
 
     let timer = howlong::ProcessCPUTimer::new();
-    let word_accs = crossbeam::scope(|scope| {
+    let cols = crossbeam::scope(|scope| -> Result<Vec<Vec<f64>>> {
         let rcv = corpus.gen_doc_bows(scope, &vocab);
-        let mut acc = BTreeMap::<u32, AccElement>::new();
-        for (doc_words_total, doc_word_counts) in rcv.into_iter() {
-            for (elem, cnt) in doc_word_counts.into_iter() {
-                let left = acc.entry(elem).or_insert(AccElement::zero());
-                let word_count = word_counts[elem as usize];
-                *left = reduce_word(left, &acc_word(cnt, word_count, doc_words_total, total_words, num_docs));
-            }
-            /*let left = acc.entry(elem).or_insert(AccElement::zero());
-            *left = reduce_word(left, &div);*/
+        let mut acc = MetricsAcc::new(metrics, word_counts.len());
+        // Any error is the last document so there's nothing left to receive after it
+        for doc in rcv.into_iter() {
+            acc.add_doc(&doc?, &word_counts, total_words);
         }
-        acc
-    }).unwrap();
-    let mut cols = FinalColumns::with_capacity(total_words as usize);
-    word_accs.into_iter().for_each(|(word_id, elem)| {
-        norm_word(&mut cols, elem, word_counts[word_id as usize], total_words, num_docs)
-    });
+        Ok(acc.finalise(&word_counts, total_words, num_docs))
+    }).unwrap()?;
     println!("Gather KL divergences {}", timer.elapsed());
     let timer = howlong::ProcessCPUTimer::new();
     let (mut words, index): (Vec<Box<[u8]>>, Vec<u32>) = vocab.into_iter().unzip();
@@ -107,32 +166,72 @@ fn process_corpus(corpus: &Box<dyn Corpus>, output: &str) {
     words.as_mut_slice().apply_inverse_permutation(index_islice.as_mut_slice());
     println!("Postprocessing of KL divergences {}", timer.elapsed());
     let timer = howlong::ProcessCPUTimer::new();
-    write_parquet(
+    let non_utf8_summary = write_parquet(
         Path::new(output),
         words.as_slice(),
         word_counts.as_slice(),
-        &[
-            "kl_div",
-            "idf",
-        ],
-        &[
-            cols.kl_div.as_slice(),
-            cols.idf.as_slice(),
-        ]
-    );
+        &metrics.iter().map(|metric| metric.name).collect_vec(),
+        &cols.iter().map(|col| col.as_slice()).collect_vec(),
+        non_utf8
+    )?;
     println!("Writing to parquet file {}", timer.elapsed());
+    if non_utf8_summary.types > 0 {
+        let action = match non_utf8 {
+            NonUtf8Words::Replace => "had invalid bytes replaced",
+            NonUtf8Words::Drop => "were dropped",
+            NonUtf8Words::Binary => "were written as is to the binary word column",
+        };
+        println!(
+            "{} word types ({} tokens) were not valid UTF-8 and {}",
+            non_utf8_summary.types,
+            non_utf8_summary.tokens,
+            action
+        );
+    }
+    Ok(())
 }
 
-fn main() {
-    let args: MkDisp = argh::from_env();
+fn run(args: MkDisp) -> Result<()> {
+    set_thread_config(ThreadConfig {
+        extract: args.threads,
+        decompress: args.decompress_threads.unwrap_or(args.threads),
+        count: args.count_threads,
+    });
 
-    if args.input.len() == 0 {
-        panic!("Need at least one input")
-    } else if args.input.len() > 1 {
-        panic!("Multiple inputs not supported yet")
+    let corpus_paths = args.input.iter().map(Path::new).collect_vec();
+    let token_key = if args.lemma { TokenKey::Lemma } else { TokenKey::WordForm };
+    let mut options = CorpusOptions::new(token_key);
+    if let Some(doc_unit) = args.fallback_doc_unit {
+        options.conllu.fallback_doc_unit = doc_unit;
+    }
+    options.conllu.multiword = args.multiword_tokens;
+    options.conllu.columns = args.columns;
+    options.vrt.attr = args.vrt_attr;
+    options.vrt.doc_unit = args.vrt_doc_unit;
+    options.vrt.token_elem = args.vrt_token_elem;
+    options.vrt.lenient = args.vrt_lenient;
+    options.errors = ErrorLog::new(args.on_error);
+    let metrics = match args.metrics {
+        Some(names) => parse_metrics(&names).map_err(|err| Error::Config(err.to_string()))?,
+        None => METRICS.iter().collect_vec(),
+    };
+    let corpus = get_multi_corpus(&corpus_paths, &args.corpus_type, &options)?;
+    process_corpus(&corpus, &args.output, &metrics, args.non_utf8_words)?;
+    if args.on_error == OnError::Skip {
+        let report_path = match args.error_report {
+            Some(report_path) => report_path,
+            None => format!("{}.errors.jsonl", args.output),
+        };
+        options.errors.write_report(Path::new(&report_path))?;
+        println!("Skipped {} entries. See {}", options.errors.skipped().len(), report_path);
     }
+    Ok(())
+}
 
-    let corpus_path = Path::new(&args.input[0]);
-    let corpus = get_corpus(corpus_path, args.corpus_type.unwrap());
-    process_corpus(&corpus, &args.output)
+fn main() {
+    let args: MkDisp = argh::from_env();
+    if let Err(err) = run(args) {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
 }
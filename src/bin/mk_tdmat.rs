@@ -1,18 +1,19 @@
 use std::path::Path;
 use std::thread;
-use crossbeam_channel::unbounded;
-use wordfreak::termdocmat::TermDocMatWriter;
+use crossbeam_channel::{unbounded, Receiver};
+use wordfreak::termdocmat::{DocMetaWriter, TermDocMatWriter};
 use wordfreak::vocab::get_numberbatch_vocab;
 use argh::FromArgs;
-use wordfreak::corpus::{CorpusType, get_corpus};
-use wordfreak::types::Corpus;
+use wordfreak::parallel::{ThreadConfig, set_thread_config};
+use wordfreak::corpus::{CorpusType, CorpusOptions, get_corpus};
+use wordfreak::conllu::{ColumnSelection, DocUnit, MultiwordTokens};
+use wordfreak::vrt::{AttrSelection, VrtDocUnit};
+use wordfreak::error::{Error, ErrorLog, OnError, Result};
+use wordfreak::types::{Corpus, TokenKey};
 use wordfreak::vocab::VocabMap;
 use crossbeam::thread::Scope;
 
 
-static LEMMA_KEY: &[u8] = b"lemma";
-
-
 #[derive(FromArgs)]
 /// add
 struct MkTdMat {
@@ -24,6 +25,71 @@ struct MkTdMat {
     #[argh(option)]
     vocab: Option<String>,
 
+    /// count word forms rather than lemmas, which are counted by default
+    #[argh(switch)]
+    word_forms: bool,
+
+    /// columns of CoNLL-U files to count, e.g. LEMMA or LEMMA+UPOS. Overrides --word-forms for
+    /// CoNLL-U.
+    #[argh(option)]
+    columns: Option<ColumnSelection>,
+
+    /// positional attribute of VRT files to count, e.g. lemma or pos. Overrides --word-forms
+    /// for VRT.
+    #[argh(option)]
+    vrt_attr: Option<AttrSelection>,
+
+    /// structural element making up documents in VRT files: text, paragraph, sentence or
+    /// element:<name>. Defaults to text.
+    #[argh(option, default = "VrtDocUnit::Text")]
+    vrt_doc_unit: VrtDocUnit,
+
+    /// element of VRT files to take tokens from. Defaults to sentences or paragraphs, or the
+    /// whole document if it has neither.
+    #[argh(option)]
+    vrt_token_elem: Option<String>,
+
+    /// log and skip malformed lines in VRT files, such as stray tags or bad entities, instead
+    /// of stopping
+    #[argh(switch)]
+    vrt_lenient: bool,
+
+    /// document unit for CoNLL-U files without # newdoc comments: paragraph, sentence or
+    /// window:<size>. Defaults to windows of 1000 tokens.
+    #[argh(option)]
+    fallback_doc_unit: Option<DocUnit>,
+
+    /// whether to count CoNLL-U multiword tokens as their syntactic words or as the surface
+    /// token: words or surface. Defaults to words.
+    #[argh(option, default = "MultiwordTokens::Words")]
+    multiword_tokens: MultiwordTokens,
+
+    /// also write the metadata of each document, such as VRT <text> attributes or CoNLL-U
+    /// newdoc ids, to doc_meta.jsonl
+    #[argh(switch)]
+    doc_meta: bool,
+
+    /// what to do with archive entries which can't be read: skip or fail. Defaults to fail.
+    #[argh(option, default = "OnError::Fail")]
+    on_error: OnError,
+
+    /// where to write a JSON line for each skipped entry with --on-error=skip. Defaults to
+    /// errors.jsonl in the output directory.
+    #[argh(option)]
+    error_report: Option<String>,
+
+    /// number of threads reading the corpus and extracting tokens
+    #[argh(option, default = "4")]
+    threads: usize,
+
+    /// number of threads decompressing archive entries. Defaults to --threads.
+    #[argh(option)]
+    decompress_threads: Option<usize>,
+
+    /// number of threads merging counts
+    #[argh(option, default = "1")]
+    count_threads: usize,
+
     /// path
     #[argh(positional)]
     output: String,
@@ -34,40 +100,93 @@ struct MkTdMat {
 }
 
 
-fn vocab_from_corpus(corpus: &Box<dyn Corpus>) -> VocabMap {
-    let (vocab_builder, _doc_count) = corpus.count_words();
+fn vocab_from_corpus(corpus: &Box<dyn Corpus>) -> Result<VocabMap> {
+    let (vocab_builder, _doc_count) = corpus.count_words()?;
     let (vocab, _word_freqs_indexed, _total_words) = vocab_builder.build();
-    vocab
+    Ok(vocab)
 }
 
+/// Writes each document with `write_doc` until there is an error. Dropping `rcv` then stops the
+/// reader threads.
+fn write_docs<T, F: FnMut(T) -> Result<()>>(rcv: Receiver<Result<T>>, mut write_doc: F) -> Result<()> {
+    for doc in rcv {
+        write_doc(doc?)?;
+    }
+    Ok(())
+}
 
-fn main() {
-    let args: MkTdMat = argh::from_env();
+
+fn run(args: MkTdMat) -> Result<()> {
+    set_thread_config(ThreadConfig {
+        extract: args.threads,
+        decompress: args.decompress_threads.unwrap_or(args.threads),
+        count: args.count_threads,
+    });
     let corpus_path = Path::new(&args.input);
-    let corpus = get_corpus(corpus_path, args.corpus_type.unwrap());
+    let corpus_type = args.corpus_type.ok_or_else(|| Error::Config("--corpus-type is required".to_owned()))?;
+    let token_key = if args.word_forms { TokenKey::WordForm } else { TokenKey::Lemma };
+    let mut options = CorpusOptions::new(token_key);
+    if let Some(doc_unit) = args.fallback_doc_unit {
+        options.conllu.fallback_doc_unit = doc_unit;
+    }
+    options.conllu.multiword = args.multiword_tokens;
+    options.conllu.columns = args.columns;
+    options.vrt.attr = args.vrt_attr;
+    options.vrt.doc_unit = args.vrt_doc_unit;
+    options.vrt.token_elem = args.vrt_token_elem;
+    options.vrt.lenient = args.vrt_lenient;
+    options.errors = ErrorLog::new(args.on_error);
+    let corpus = get_corpus(corpus_path, corpus_type, &options)?;
     let vocab = if let Some(vocab_path) = args.vocab {
         println!("Reading vocab");
-        get_numberbatch_vocab(&vocab_path)
+        get_numberbatch_vocab(&vocab_path)?
     } else {
         println!("Scanning vocab");
-        vocab_from_corpus(&corpus)
+        vocab_from_corpus(&corpus)?
     };
     println!("Vocab size: {}", vocab.len());
 
     println!("Reading and writing other files");
     let out_dir = Path::new(&args.output);
 
-    let mut writer = TermDocMatWriter::new(out_dir, vocab.len() as u64);
+    let mut writer = TermDocMatWriter::new(out_dir, vocab.len() as u64)?;
 
-    crossbeam::scope(|scope| {
-        let rcv = corpus.gen_doc_bows(scope, &vocab);
-        for (doc_words, counts) in rcv.into_iter() {
-             writer.write_indexed_doc(doc_words as u64, &counts);
+    let doc_meta = args.doc_meta;
+    crossbeam::scope(|scope| -> Result<()> {
+        if doc_meta {
+            let mut meta_writer = DocMetaWriter::new(out_dir)?;
+            let rcv = corpus.gen_doc_bows_with_meta(scope, &vocab);
+            write_docs(rcv, |((_doc_words, counts), meta)| {
+                writer.write_indexed_doc(&counts)?;
+                meta_writer.write_doc_meta(&meta)
+            })?;
+            meta_writer.close()?;
+        } else {
+            let rcv = corpus.gen_doc_bows(scope, &vocab);
+            write_docs(rcv, |(_doc_words, counts)| writer.write_indexed_doc(&counts))?;
         }
-        let (num_docs, vocab_len, num_values) = writer.close();
+        let (num_docs, vocab_len, num_values) = writer.close()?;
         println!("Vocab size: {}", vocab_len);
         println!("Num docs: {}", num_docs);
         println!("Num values: {}", num_values);
         println!("Density: {}", (num_values as f64) / ((num_docs * (vocab_len as u64))) as f64);
-    }).unwrap();
+        Ok(())
+    }).unwrap()?;
+    if args.on_error == OnError::Skip {
+        let report_path = match args.error_report {
+            Some(report_path) => Path::new(&report_path).to_owned(),
+            None => out_dir.join("errors.jsonl"),
+        };
+        options.errors.write_report(&report_path)?;
+        println!("Skipped {} entries. See {}", options.errors.skipped().len(), report_path.display());
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: MkTdMat = argh::from_env();
+    if let Err(err) = run(args) {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
 }
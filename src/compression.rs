@@ -0,0 +1,209 @@
+use std::path::Path;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use flate2::read::MultiGzDecoder;
+use flate2::bufread::GzDecoder;
+use xz2::read::XzDecoder;
+use bzip2::read::MultiBzDecoder;
+use crossbeam_channel::{bounded, unbounded, Receiver};
+use crossbeam::thread::Scope;
+use crate::parallel::thread_config;
+
+
+/// How many BGZF blocks (each up to 64KiB uncompressed) a decompression thread takes at a time
+const BGZF_BLOCKS_PER_BATCH: usize = 64;
+
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Bzip2,
+    Zstd,
+}
+
+/// Works out the compression format from the magic bytes at the start of a file
+pub fn sniff_compression(magic: &[u8]) -> Compression {
+    if magic.starts_with(&[0x1f, 0x8b]) {
+        Compression::Gzip
+    } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        Compression::Xz
+    } else if magic.starts_with(b"BZh") {
+        Compression::Bzip2
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Compression::Zstd
+    } else {
+        Compression::None
+    }
+}
+
+/// Wraps `reader` in a streaming decoder for `compression`. All decoders carry on through
+/// concatenated streams, as produced by e.g. `cat a.gz b.gz` or pigz.
+pub fn decompress_reader<'a, R: Read + Send + 'a>(reader: R, compression: Compression) -> io::Result<Box<dyn Read + Send + 'a>> {
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Compression::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+        Compression::Bzip2 => Box::new(MultiBzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+    })
+}
+
+/// Opens a possibly compressed file, decompressing it as it is read
+pub fn open_decompressed(path: &Path) -> io::Result<BufReader<Box<dyn Read + Send>>> {
+    let mut magic = Vec::with_capacity(6);
+    File::open(path)?.take(6).read_to_end(&mut magic)?;
+    let file = File::open(path)?;
+    Ok(BufReader::new(decompress_reader(file, sniff_compression(&magic))?))
+}
+
+/// Finds the blocks of a BGZF file (as written by bgzip), which are gzip members recording their
+/// own compressed size in a `BC` extra field. Returns None if any member is not a BGZF block.
+pub fn bgzf_blocks(buf: &[u8]) -> Option<Vec<Range<usize>>> {
+    let mut blocks = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let header = buf.get(pos..pos + 12)?;
+        // ID1 ID2 CM=deflate FLG=FEXTRA
+        if header[..4] != [0x1f, 0x8b, 0x08, 0x04] {
+            return None;
+        }
+        let xlen = u16::from_le_bytes([header[10], header[11]]) as usize;
+        let mut extra = buf.get(pos + 12..pos + 12 + xlen)?;
+        let mut block_size = None;
+        while extra.len() >= 4 {
+            let slen = u16::from_le_bytes([extra[2], extra[3]]) as usize;
+            if extra[..2] == *b"BC" && slen == 2 && extra.len() >= 6 {
+                block_size = Some(u16::from_le_bytes([extra[4], extra[5]]) as usize + 1);
+            }
+            extra = extra.get(4 + slen..)?;
+        }
+        let end = pos + block_size?;
+        if end > buf.len() {
+            return None;
+        }
+        blocks.push(pos..end);
+        pos = end;
+    }
+    Some(blocks)
+}
+
+/// Reads buffers from a channel in order as one continuous stream. An error from the channel is
+/// returned from `read` in place of the buffer.
+pub struct ChannelReader {
+    rcv: Receiver<io::Result<Vec<u8>>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl ChannelReader {
+    pub fn new(rcv: Receiver<io::Result<Vec<u8>>>) -> ChannelReader {
+        ChannelReader { rcv, buf: Vec::new(), pos: 0 }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            match self.rcv.recv() {
+                Ok(Ok(buf)) => {
+                    self.buf = buf;
+                    self.pos = 0;
+                },
+                Ok(Err(err)) => return Err(err),
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = out.len().min(self.buf.len() - self.pos);
+        out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// Decompresses the BGZF `blocks` of `buf` using the decompression threads, returning a reader
+/// over the decompressed data in the original order
+pub fn parallel_bgzf_reader<'env>(
+    scope: &Scope<'env>,
+    buf: &'env [u8],
+    blocks: &'env [Range<usize>]
+) -> ChannelReader {
+    let threads = thread_config().decompress;
+    let (batch_snd, batch_rcv) = unbounded();
+    for batch in blocks.chunks(BGZF_BLOCKS_PER_BATCH).enumerate() {
+        batch_snd.send(batch).unwrap();
+    }
+    drop(batch_snd);
+    let (decompressed_snd, decompressed_rcv) = bounded::<(usize, io::Result<Vec<u8>>)>(threads * 2);
+    for _ in 0..threads {
+        let batch_rcv_clone = batch_rcv.clone();
+        let decompressed_snd_clone = decompressed_snd.clone();
+        scope.spawn(move |_| {
+            for (idx, batch) in batch_rcv_clone {
+                let mut decompressed = Vec::new();
+                let result = batch.iter().try_for_each(|block| {
+                    GzDecoder::new(&buf[block.clone()]).read_to_end(&mut decompressed).map(|_| ())
+                });
+                if decompressed_snd_clone.send((idx, result.map(|()| decompressed))).is_err() {
+                    // The reader has stopped at an earlier error
+                    break;
+                }
+            }
+        });
+    }
+    drop(decompressed_snd);
+    // Batches can finish out of order so hold them back until all the ones before have been sent.
+    // Nothing after the first error is sent since the reader stops there.
+    let (ordered_snd, ordered_rcv) = bounded(threads * 2);
+    scope.spawn(move |_| {
+        let mut pending = BTreeMap::new();
+        let mut next_idx = 0;
+        for (idx, decompressed) in decompressed_rcv {
+            pending.insert(idx, decompressed);
+            while let Some(decompressed) = pending.remove(&next_idx) {
+                let is_err = decompressed.is_err();
+                if ordered_snd.send(decompressed).is_err() || is_err {
+                    return;
+                }
+                next_idx += 1;
+            }
+        }
+    });
+    ChannelReader::new(ordered_rcv)
+}
+
+/// Reads lines from `reader` into chunks of at least `chunk_size` bytes, each of which apart
+/// from the first starts with a line satisfying `is_boundary`, and passes them to `proc_chunk`.
+/// Reading stops early if `proc_chunk` returns false. On a read error the chunks before it have
+/// already been passed on.
+pub fn split_stream_at_boundaries<R: BufRead, F: FnMut(Vec<u8>) -> bool>(
+    mut reader: R,
+    chunk_size: usize,
+    is_boundary: fn(&[u8]) -> bool,
+    mut proc_chunk: F
+) -> io::Result<()> {
+    let mut chunk = Vec::with_capacity(chunk_size);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+        if read == 0 {
+            break;
+        }
+        if chunk.len() >= chunk_size {
+            let trimmed = line.strip_suffix(b"\n").unwrap_or(&line);
+            if is_boundary(trimmed) && !proc_chunk(std::mem::replace(&mut chunk, Vec::with_capacity(chunk_size))) {
+                return Ok(());
+            }
+        }
+        chunk.extend_from_slice(&line);
+    }
+    if !chunk.is_empty() {
+        proc_chunk(chunk);
+    }
+    Ok(())
+}
@@ -1,147 +1,959 @@
-use crate::types::{Corpus, DocBow};
+use crate::types::{Corpus, DocBow, DocMeta, TokenKey};
 use crate::vocab::VocabMap;
 use std::path::Path;
-use std::io::{BufReader, BufRead};
+use std::ffi::OsStr;
+use std::str::FromStr;
+use simple_error::SimpleError;
+use std::io::{self, BufRead, BufReader, Read};
 use std::fs::File;
 use std::collections::BTreeMap;
-use crossbeam_channel::{Receiver, bounded};
+use std::ops::Range;
+use memmap::Mmap;
+use crossbeam_channel::{Receiver, bounded, unbounded};
 use crossbeam::thread::Scope;
 use crate::vocab::VocabBuilder;
+use crate::parallel::{largest_first_queue, parallel_reduce, thread_config};
+use crate::zip::{MinEntries, MinEntry, compressed_size, entry_path, filter_zip_entries, is_zip, mmap_file, read_buf};
+use crate::error::{Error, ErrorLog};
+use piz::ZipArchive;
+use piz::read::FileMetadata;
+use crate::compression::{Compression, bgzf_blocks, decompress_reader, parallel_bgzf_reader, sniff_compression, split_stream_at_boundaries};
 
 
-pub fn grab_lemma(line: &[u8]) -> &[u8] {
-    line.split(|chr| *chr == b'\t').nth(2).unwrap()
+pub const ID_COLUMN: usize = 0;
+pub const FORM_COLUMN: usize = 1;
+pub const LEMMA_COLUMN: usize = 2;
+pub const DEFAULT_FALLBACK_DOC_UNIT: DocUnit = DocUnit::TokenWindow(1000);
+/// The columns of plain CoNLL-U files, which CoNLL-U Plus files replace with `# global.columns`
+pub const CONLLU_COLUMNS: [&str; 10] = [
+    "ID", "FORM", "LEMMA", "UPOS", "XPOS", "FEATS", "HEAD", "DEPREL", "DEPS", "MISC"
+];
+/// Roughly how much text each extraction thread takes at a time. Plain and compressed files are
+/// split the same way whatever the number of threads, so that documents which depend on the
+/// chunks, such as token windows, are always the same. The start of compressed files up to this
+/// size is also used to detect their columns and document unit.
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+/// Goes between the values of combined columns such as LEMMA+UPOS. Tabs can't occur within a
+/// column so the values can always be split apart again.
+pub const COMBINED_COLUMN_SEPARATOR: u8 = b'\t';
+
+
+pub fn token_key_column(token_key: TokenKey) -> usize {
+    match token_key {
+        TokenKey::WordForm => FORM_COLUMN,
+        TokenKey::Lemma => LEMMA_COLUMN,
+    }
+}
+
+/// Gets a column from a token line, or None if the line has too few columns
+pub fn grab_column(line: &[u8], column: usize) -> Option<&[u8]> {
+    line.split(|chr| *chr == b'\t').nth(column)
+}
+
+pub fn grab_lemma(line: &[u8]) -> Option<&[u8]> {
+    grab_column(line, LEMMA_COLUMN)
+}
+
+/// The ID column of a CoNLL-U token line
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TokenId {
+    /// A syntactic word, e.g. `3`
+    Word(u32),
+    /// A multiword token spanning a range of syntactic words, e.g. `1-2`
+    Range(u32, u32),
+    /// An empty node of the enhanced representation, e.g. `8.1`
+    Empty,
+}
+
+fn parse_id_num(num: &[u8]) -> Result<u32, Error> {
+    match std::str::from_utf8(num).ok().and_then(|num| num.parse().ok()) {
+        Some(num) => Ok(num),
+        None => Err(Error::Format(format!("Invalid CoNLL-U token ID {}", String::from_utf8_lossy(num)))),
+    }
+}
+
+pub fn parse_id(id: &[u8]) -> Result<TokenId, Error> {
+    Ok(if id.contains(&b'.') {
+        TokenId::Empty
+    } else if let Some(dash_pos) = id.iter().position(|chr| *chr == b'-') {
+        TokenId::Range(parse_id_num(&id[..dash_pos])?, parse_id_num(&id[dash_pos + 1..])?)
+    } else {
+        TokenId::Word(parse_id_num(id)?)
+    })
+}
+
+/// Which tokens to count where a multiword token such as Spanish `del` is split into syntactic
+/// words `de el`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MultiwordTokens {
+    /// The syntactic words, as the lemmas and POS tags belong to them
+    Words,
+    /// The surface token as it appeared in the text
+    Surface,
+}
+
+impl FromStr for MultiwordTokens {
+    type Err = SimpleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "words" {
+            Ok(MultiwordTokens::Words)
+        } else if s == "surface" {
+            Ok(MultiwordTokens::Surface)
+        } else {
+            Err(SimpleError::new("Must be words or surface"))
+        }
+    }
 }
 
+/// Column names to build tokens from, e.g. `LEMMA` or `LEMMA+UPOS` for POS disambiguated lemmas
+#[derive(Clone, Debug)]
+pub struct ColumnSelection {
+    names: Vec<String>,
+}
+
+impl FromStr for ColumnSelection {
+    type Err = SimpleError;
 
-pub fn open_conllu(path: &Path) -> BufReader<File> {
-    let file = File::open(path).unwrap();
-    BufReader::new(file)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let names = s.split('+').map(|name| name.trim().to_ascii_uppercase()).collect::<Vec<_>>();
+        if names.iter().any(|name| name.is_empty()) {
+            return Err(SimpleError::new("Must be column names separated by +, e.g. LEMMA+UPOS"));
+        }
+        Ok(ColumnSelection { names })
+    }
 }
 
-struct FlatTokenIter {
-    buf_read: BufReader<File>,
-    line_buf: Vec<u8>,
-    doc_count: u32
+impl ColumnSelection {
+    pub fn from_token_key(token_key: TokenKey) -> ColumnSelection {
+        let column = token_key_column(token_key);
+        ColumnSelection { names: vec![CONLLU_COLUMNS[column].to_owned()] }
+    }
 }
 
-impl<'a> FlatTokenIter {
-    fn new(buf_read: BufReader<File>) -> FlatTokenIter {
-        FlatTokenIter {
-            buf_read,
-            line_buf: Vec::with_capacity(200),
-            doc_count: 0,
+/// Reads the column names from a `# global.columns = ...` first line, returning None for plain
+/// CoNLL-U
+pub fn parse_global_columns(buf: &[u8]) -> Option<Vec<String>> {
+    let first_line = trim_cr(buf.split(|chr| *chr == b'\n').next()?);
+    match parse_comment(first_line)? {
+        (b"global.columns", Some(columns)) => Some(
+            String::from_utf8_lossy(columns).split_ascii_whitespace().map(|name| name.to_ascii_uppercase()).collect()
+        ),
+        _ => None,
+    }
+}
+
+/// What to take from each token line of a CoNLL-U file, with columns resolved to indices
+#[derive(Clone, Debug)]
+pub struct ConlluTokens {
+    pub columns: Vec<usize>,
+    pub id_column: Option<usize>,
+    pub form_column: Option<usize>,
+    pub lemma_column: Option<usize>,
+    pub multiword: MultiwordTokens,
+}
+
+impl ConlluTokens {
+    pub fn new(token_key: TokenKey) -> ConlluTokens {
+        ConlluTokens {
+            columns: vec![token_key_column(token_key)],
+            id_column: Some(ID_COLUMN),
+            form_column: Some(FORM_COLUMN),
+            lemma_column: Some(LEMMA_COLUMN),
+            multiword: MultiwordTokens::Words,
         }
     }
 
-    pub fn next_token<R, F: FnMut(&[u8]) -> R>(&mut self, mut proc_token: F) -> Option<R> {
-        loop {
-            self.line_buf.clear();
-            let line = self.buf_read.read_until(b'\n', &mut self.line_buf);
-            let read = line.unwrap();
-            if read == 0 {
-                return None;
-            } else if self.line_buf == b"# newdoc\n" {
-                self.doc_count += 1;
-            } else if self.line_buf[0] != b'#' && self.line_buf[0] != b'\n' {
-                return Some(proc_token(grab_lemma(self.line_buf.as_slice())));
+    /// Looks up the selected columns in `column_names`, which are all required
+    pub fn resolve(
+        selection: &ColumnSelection,
+        column_names: &[String],
+        multiword: MultiwordTokens
+    ) -> Result<ConlluTokens, Error> {
+        let find_column = |name: &str| column_names.iter().position(|column_name| column_name == name);
+        let columns = selection.names.iter().map(|name| {
+            find_column(name).ok_or_else(|| Error::Config(
+                format!("No column {} in CoNLL-U file with columns {}", name, column_names.join(" "))
+            ))
+        }).collect::<Result<_, _>>()?;
+        Ok(ConlluTokens {
+            columns,
+            id_column: find_column("ID"),
+            form_column: find_column("FORM"),
+            lemma_column: find_column("LEMMA"),
+            multiword,
+        })
+    }
+
+    /// Gets a single column from a line. Missing lemmas (`_`) fall back to the word form, which
+    /// is also the case for all multiword tokens since they don't have lemmas.
+    fn grab_value<'a>(&self, line: &'a [u8], column: usize) -> Option<&'a [u8]> {
+        let value = grab_column(line, column)?;
+        match self.form_column {
+            Some(form_column) if value == b"_" && Some(column) == self.lemma_column => {
+                grab_column(line, form_column)
+            },
+            _ => Some(value),
+        }
+    }
+
+    /// Gets the token from a line, or None if the line has too few columns. Combined columns are
+    /// joined into `scratch`.
+    pub fn grab<'a>(&self, line: &'a [u8], scratch: &'a mut Vec<u8>) -> Option<&'a [u8]> {
+        if let [column] = self.columns[..] {
+            return self.grab_value(line, column);
+        }
+        scratch.clear();
+        for (idx, column) in self.columns.iter().enumerate() {
+            if idx > 0 {
+                scratch.push(COMBINED_COLUMN_SEPARATOR);
             }
+            scratch.extend_from_slice(self.grab_value(line, *column)?);
+        }
+        Some(scratch)
+    }
+}
+
+/// Options for reading CoNLL-U files
+#[derive(Clone, Debug)]
+pub struct ConlluOptions {
+    /// Columns to take tokens from. Defaults to FORM or LEMMA depending on the token key.
+    pub columns: Option<ColumnSelection>,
+    pub multiword: MultiwordTokens,
+    /// The document unit for files without `# newdoc` comments
+    pub fallback_doc_unit: DocUnit,
+}
+
+impl Default for ConlluOptions {
+    fn default() -> ConlluOptions {
+        ConlluOptions {
+            columns: None,
+            multiword: MultiwordTokens::Words,
+            fallback_doc_unit: DEFAULT_FALLBACK_DOC_UNIT,
         }
     }
 }
 
-impl<'a> Iterator for FlatTokenIter {
-    type Item = Box<[u8]>;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.next_token(|x| x.to_owned().into_boxed_slice())
+pub fn open_conllu(path: &Path) -> io::Result<BufReader<File>> {
+    let file = File::open(path)?;
+    Ok(BufReader::new(file))
+}
+
+fn is_conllu_file(entry: &FileMetadata) -> bool {
+    entry.is_file() && entry.path.extension().and_then(OsStr::to_str) == Some("conllu")
+}
+
+/// What makes up a document in a CoNLL-U file
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DocUnit {
+    /// Started by `# newdoc` comments
+    NewDoc,
+    /// Started by `# newpar` comments
+    Paragraph,
+    /// Ended by a blank line
+    Sentence,
+    /// A fixed number of tokens. Windows are not carried over between the chunks of about 4 MiB
+    /// read by different threads, so the last window of each chunk can be shorter.
+    TokenWindow(usize),
+}
+
+impl FromStr for DocUnit {
+    type Err = SimpleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "newdoc" {
+            Ok(DocUnit::NewDoc)
+        } else if s == "paragraph" {
+            Ok(DocUnit::Paragraph)
+        } else if s == "sentence" {
+            Ok(DocUnit::Sentence)
+        } else if let Some(size) = s.strip_prefix("window:") {
+            match size.parse() {
+                Ok(size) if size > 0 => Ok(DocUnit::TokenWindow(size)),
+                _ => Err(SimpleError::new("Window size must be a positive integer")),
+            }
+        } else {
+            Err(SimpleError::new("Must be newdoc, paragraph, sentence or window:<size>"))
+        }
     }
 }
 
-struct DocBowIter<'a> {
-    buf_read: BufReader<File>,
-    line_buf: Vec<u8>,
-    is_first: bool,
-    vocab: &'a VocabMap
+impl DocUnit {
+    /// Lines at which the file can be split between threads without changing the documents
+    fn chunk_boundary(&self) -> fn(&[u8]) -> bool {
+        match self {
+            DocUnit::NewDoc => is_newdoc,
+            DocUnit::Paragraph => is_newpar,
+            DocUnit::Sentence | DocUnit::TokenWindow(_) => is_blank,
+        }
+    }
 }
 
-impl<'a> DocBowIter<'a> {
-    fn new(buf_read: BufReader<File>, vocab: &'a VocabMap) -> DocBowIter<'a> {
-        DocBowIter {
-            buf_read,
-            line_buf: Vec::with_capacity(200),
-            is_first: true,
-            vocab,
+pub(crate) fn trim_ascii(mut bytes: &[u8]) -> &[u8] {
+    while let [first, rest @ ..] = bytes {
+        if !first.is_ascii_whitespace() {
+            break;
+        }
+        bytes = rest;
+    }
+    while let [rest @ .., last] = bytes {
+        if !last.is_ascii_whitespace() {
+            break;
         }
+        bytes = rest;
     }
+    bytes
 }
 
-impl<'a> Iterator for DocBowIter<'a> {
-    type Item = DocBow;
+/// Splits a comment line such as `# newdoc id = n01001` into its key and value
+pub fn parse_comment(line: &[u8]) -> Option<(&[u8], Option<&[u8]>)> {
+    let comment = line.strip_prefix(b"#")?;
+    match comment.iter().position(|chr| *chr == b'=') {
+        Some(eq_pos) => Some((trim_ascii(&comment[..eq_pos]), Some(trim_ascii(&comment[eq_pos + 1..])))),
+        None => Some((trim_ascii(comment), None)),
+    }
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut doc_words = 0;
-        let mut counts: BTreeMap<u32, u32> = BTreeMap::new();
+/// Parses `# <name>` and `# <name> id = <id>` comments, returning the id if there is one
+pub fn parse_boundary_comment<'a>(line: &'a [u8], name: &[u8]) -> Option<Option<&'a [u8]>> {
+    let (key, value) = parse_comment(line)?;
+    let mut key_words = key.split(|chr| chr.is_ascii_whitespace()).filter(|word| !word.is_empty());
+    if key_words.next()? != name {
+        return None;
+    }
+    match (key_words.next(), key_words.next(), value) {
+        (None, _, None) => Some(None),
+        (Some(b"id"), None, Some(id)) => Some(Some(id)),
+        _ => None,
+    }
+}
 
-        loop {
-            self.line_buf.clear();
-            let line = self.buf_read.read_until(b'\n', &mut self.line_buf);
-            let read = line.unwrap();
-            if read == 0 {
-                return None;
-            } else if self.line_buf == b"# newdoc\n" {
-                if self.is_first {
-                    self.is_first = false
-                } else {
-                    return Some((doc_words, counts));
-                }
-            } else if self.line_buf[0] != b'#' && self.line_buf[0] != b'\n' {
-                let lemma = grab_lemma(self.line_buf.as_slice());
-                let maybe_vocab_idx = self.vocab.get(lemma);
-                if let Some(vocab_idx) = maybe_vocab_idx {
-                    *counts.entry(*vocab_idx).or_insert(0) += 1;
-                    doc_words += 1;
+fn trim_cr(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+pub fn is_newdoc(line: &[u8]) -> bool {
+    parse_boundary_comment(trim_cr(line), b"newdoc").is_some()
+}
+
+pub fn is_newpar(line: &[u8]) -> bool {
+    parse_boundary_comment(trim_cr(line), b"newpar").is_some()
+}
+
+pub fn is_blank(line: &[u8]) -> bool {
+    trim_cr(line).is_empty()
+}
+
+fn too_few_columns(line: &[u8]) -> Error {
+    Error::Format(format!("Too few columns in CoNLL-U token line {}", String::from_utf8_lossy(line)))
+}
+
+pub enum ConlluEvent<'a> {
+    Token(&'a [u8]),
+    /// The end of a document along with its id from `# newdoc id = ...` if it had one
+    DocEnd(Option<&'a [u8]>),
+}
+
+/// The tokens of a document which hasn't ended yet, copied end to end
+#[derive(Default)]
+struct PendingDoc {
+    tokens: Vec<u8>,
+    ends: Vec<usize>,
+}
+
+impl PendingDoc {
+    fn push(&mut self, tok: &[u8]) {
+        self.tokens.extend_from_slice(tok);
+        self.ends.push(self.tokens.len());
+    }
+
+    fn clear(&mut self) {
+        self.tokens.clear();
+        self.ends.clear();
+    }
+
+    /// Passes on the tokens followed by the end of the document
+    fn end<F: FnMut(ConlluEvent)>(&mut self, id: Option<&[u8]>, proc_event: &mut F) {
+        let mut start = 0;
+        for &end in self.ends.iter() {
+            proc_event(ConlluEvent::Token(&self.tokens[start..end]));
+            start = end;
+        }
+        proc_event(ConlluEvent::DocEnd(id));
+        self.clear();
+    }
+}
+
+/// Calls `proc_event` for every token in `buf` and at the end of every document as delimited by
+/// `doc_unit`. Any tokens before the first delimiter form a document of their own.
+///
+/// A malformed token line is passed to `proc_error` along with its byte offset in `buf`. The
+/// document it is in is dropped and reading carries on from the next document boundary.
+pub fn for_each_conllu_event<'a, F: FnMut(ConlluEvent), E: FnMut(usize, Error)>(
+    buf: &'a [u8],
+    tokens: &ConlluTokens,
+    doc_unit: DocUnit,
+    mut proc_event: F,
+    mut proc_error: E
+) {
+    // Tokens are only passed on once their document has ended without a malformed line
+    let mut doc = PendingDoc::default();
+    let mut in_doc = false;
+    let mut doc_id = None;
+    let mut skipping = false;
+    let mut window_tokens = 0;
+    // The last syntactic word covered by the current multiword token
+    let mut multiword_end = 0;
+    let mut scratch = Vec::new();
+    let mut line_start = 0;
+    for line in buf.split(|chr| *chr == b'\n') {
+        let line_offset = line_start;
+        line_start += line.len() + 1;
+        let line = trim_cr(line);
+        if line.is_empty() {
+            multiword_end = 0;
+            match doc_unit {
+                DocUnit::Sentence | DocUnit::TokenWindow(_) => skipping = false,
+                _ => {},
+            }
+            if doc_unit == DocUnit::Sentence && in_doc {
+                doc.end(None, &mut proc_event);
+                in_doc = false;
+            }
+        } else if line[0] == b'#' {
+            let doc_start = match doc_unit {
+                DocUnit::NewDoc => parse_boundary_comment(line, b"newdoc"),
+                DocUnit::Paragraph => parse_boundary_comment(line, b"newpar"),
+                _ => None,
+            };
+            if let Some(id) = doc_start {
+                if in_doc {
+                    doc.end(doc_id, &mut proc_event);
                 }
+                in_doc = true;
+                doc_id = id;
+                skipping = false;
+            }
+        } else if !skipping {
+            let id = match tokens.id_column {
+                Some(id_column) => grab_column(line, id_column).ok_or_else(|| too_few_columns(line)).and_then(parse_id),
+                None => Ok(TokenId::Word(0)),
+            };
+            let count_line = id.map(|id| match id {
+                TokenId::Empty => false,
+                TokenId::Range(_, end) => {
+                    multiword_end = end;
+                    tokens.multiword == MultiwordTokens::Surface
+                },
+                // Without an ID column there are no multiword tokens so every line is counted
+                TokenId::Word(id) => {
+                    tokens.multiword == MultiwordTokens::Words || tokens.id_column.is_none() || id > multiword_end
+                },
+            });
+            let tok = match count_line {
+                Ok(true) => tokens.grab(line, &mut scratch).map(Some).ok_or_else(|| too_few_columns(line)),
+                Ok(false) => Ok(None),
+                Err(err) => Err(err),
+            };
+            match tok {
+                Ok(Some(tok)) => {
+                    in_doc = true;
+                    doc.push(tok);
+                    if let DocUnit::TokenWindow(size) = doc_unit {
+                        window_tokens += 1;
+                        if window_tokens == size {
+                            doc.end(None, &mut proc_event);
+                            in_doc = false;
+                            window_tokens = 0;
+                        }
+                    }
+                },
+                Ok(None) => {},
+                Err(err) => {
+                    proc_error(line_offset, err);
+                    doc.clear();
+                    in_doc = false;
+                    skipping = true;
+                    window_tokens = 0;
+                },
             }
         }
     }
+    if in_doc {
+        doc.end(doc_id, &mut proc_event);
+    }
 }
 
-pub struct ConlluCorpus {
-    path: Box<Path>
+/// Adds the position of a malformed line to its error
+fn at_offset(offset: usize, err: Error) -> Error {
+    Error::Format(format!("{} at byte {}", err, offset))
 }
 
-impl ConlluCorpus {
-    pub fn new(path: &Path) -> ConlluCorpus {
-        ConlluCorpus {
-            path: Box::from(path),
+/// Finds the start of the first line at or after `pos` which satisfies `is_boundary`
+fn next_boundary(buf: &[u8], mut pos: usize, is_boundary: fn(&[u8]) -> bool) -> usize {
+    if pos >= buf.len() {
+        return buf.len();
+    }
+    if pos > 0 {
+        // Move to the start of the next line
+        match buf[pos - 1..].iter().position(|chr| *chr == b'\n') {
+            Some(offset) => pos += offset,
+            None => return buf.len(),
+        }
+    }
+    while pos < buf.len() {
+        let line_end = buf[pos..].iter().position(|chr| *chr == b'\n').map_or(buf.len(), |offset| pos + offset);
+        if is_boundary(&buf[pos..line_end]) {
+            return pos;
         }
+        pos = line_end + 1;
     }
+    buf.len()
+}
 
-    fn open(&self) -> BufReader<File> {
-        BufReader::new(File::open(&self.path).unwrap())
+/// Splits `buf` into byte ranges of at least `chunk_size` bytes, each of which apart from the first
+/// starts with a line satisfying `is_boundary`. The chunks are the same as those of
+/// `split_stream_at_boundaries`.
+pub fn split_at_boundaries(buf: &[u8], chunk_size: usize, is_boundary: fn(&[u8]) -> bool) -> Vec<Range<usize>> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < buf.len() {
+        let end = next_boundary(buf, start + chunk_size.max(1), is_boundary);
+        chunks.push(start..end);
+        start = end;
     }
+    chunks
 }
 
-impl Corpus for ConlluCorpus {
-    fn count_words(&self) -> (VocabBuilder, u32) {
-        let mut tokens = FlatTokenIter::new(self.open());
-        let mut vocab = VocabBuilder::new();
-        tokens.next_token(|tok| {
-            vocab.add(tok);
+/// Where a chunk passed to `ConlluSource::for_each_chunk` starts, for reporting malformed lines
+#[derive(Clone, Copy, Debug)]
+pub struct ChunkStart<'a> {
+    /// The zip entry the chunk is from, if any
+    pub path: Option<&'a Path>,
+    /// The byte offset of the chunk within its file or entry after decompression
+    pub offset: usize,
+}
+
+/// Calls `cb` on each chunk of `buf` using the extraction threads, each of which stops once `cb`
+/// returns false
+pub fn buffered_chunks<'env, F>(
+    scope: &Scope<'env>,
+    buf: &'env [u8],
+    chunks: &'env [Range<usize>],
+    cb: F
+) -> ()
+    where F: Fn(&[u8], ChunkStart) -> bool + Send + Clone + 'env
+{
+    let threads = thread_config().extract;
+    let chunk_queue = largest_first_queue(chunks, |chunk| chunk.len());
+    println!("Reading {} chunks using {} threads", chunks.len(), threads);
+    for _ in 0..threads {
+        let chunk_queue_clone = chunk_queue.clone();
+        let cb_clone = cb.clone();
+        scope.spawn(move |_| {
+            for chunk in chunk_queue_clone {
+                if !cb_clone(&buf[chunk.clone()], ChunkStart { path: None, offset: chunk.start }) {
+                    break;
+                }
+            }
         });
-        (vocab, tokens.doc_count)
     }
+}
 
-    fn gen_doc_bows<'env>(&'env self, scope: &Scope<'env>, vocab: &'env VocabMap) -> Receiver<DocBow> {
-        let (snd, rcv) = bounded(1024);
-        let file = self.open();
+/// Reads chunks split at `is_boundary` lines from `reader` on one thread and calls `cb` on them
+/// using the extraction threads. A read error is reported to `errors` and ends the stream, as
+/// does `cb` returning false on every extraction thread.
+pub fn streamed_chunks<'env, R, F>(
+    scope: &Scope<'env>,
+    reader: R,
+    is_boundary: fn(&[u8]) -> bool,
+    errors: &'env ErrorLog,
+    cb: F
+) -> ()
+    where R: BufRead + Send + 'env, F: Fn(&[u8], ChunkStart) -> bool + Send + Clone + 'env
+{
+    let threads = thread_config().extract;
+    let (snd, rcv) = bounded::<(usize, Vec<u8>)>(threads * 2);
+    scope.spawn(move |_| {
+        let mut offset = 0;
+        let result = split_stream_at_boundaries(reader, CHUNK_SIZE, is_boundary, |chunk| {
+            let chunk_offset = offset;
+            offset += chunk.len();
+            snd.send((chunk_offset, chunk)).is_ok()
+        });
+        if let Err(err) = result {
+            errors.report(None, err.into());
+        }
+    });
+    println!("Reading streamed chunks using {} threads", threads);
+    for _ in 0..threads {
+        let rcv_clone = rcv.clone();
+        let cb_clone = cb.clone();
         scope.spawn(move |_| {
-            let iter = DocBowIter::new(file, vocab);
-            for doc in iter {
-                snd.send(doc).unwrap();
+            for (offset, chunk) in rcv_clone {
+                if !cb_clone(&chunk, ChunkStart { path: None, offset }) {
+                    break;
+                }
             }
         });
-        rcv
+    }
+}
+
+/// Where the lines of a CoNLL-U corpus come from
+pub enum ConlluSource {
+    /// An uncompressed file split into chunks at document boundaries
+    Plain(Vec<Range<usize>>),
+    /// A BGZF file with the given blocks, which can be decompressed in parallel
+    Bgzf(Vec<Range<usize>>),
+    /// Any other compressed file, which is decompressed on a single thread
+    Compressed(Compression),
+    /// The CoNLL-U entries of a zip archive, which are read in parallel
+    Zip(MinEntries),
+}
+
+impl ConlluSource {
+    /// Works out how to read `buf`, splitting it between threads at `doc_unit` boundaries if it
+    /// is uncompressed
+    pub fn new(buf: &[u8], doc_unit: DocUnit) -> ConlluSource {
+        match sniff_compression(buf) {
+            Compression::None => {
+                ConlluSource::Plain(split_at_boundaries(buf, CHUNK_SIZE, doc_unit.chunk_boundary()))
+            },
+            Compression::Gzip => match bgzf_blocks(buf) {
+                Some(blocks) => ConlluSource::Bgzf(blocks),
+                None => ConlluSource::Compressed(Compression::Gzip),
+            },
+            compression => ConlluSource::Compressed(compression),
+        }
+    }
+
+    /// Calls `cb` on chunks of the decompressed `buf` using the extraction threads. Chunks start
+    /// at `doc_unit` boundaries. Read errors are reported to `errors`. Reading stops early once
+    /// `cb` returns false.
+    pub fn for_each_chunk<'env, F>(
+        &'env self,
+        scope: &Scope<'env>,
+        buf: &'env [u8],
+        doc_unit: DocUnit,
+        errors: &'env ErrorLog,
+        cb: F
+    ) -> ()
+        where F: Fn(&[u8], ChunkStart) -> bool + Send + Clone + 'env
+    {
+        match self {
+            ConlluSource::Plain(chunks) => buffered_chunks(scope, buf, chunks, cb),
+            ConlluSource::Bgzf(blocks) => {
+                let reader = BufReader::new(parallel_bgzf_reader(scope, buf, blocks));
+                streamed_chunks(scope, reader, doc_unit.chunk_boundary(), errors, cb)
+            },
+            ConlluSource::Compressed(compression) => {
+                match decompress_reader(buf, *compression) {
+                    Ok(reader) => streamed_chunks(scope, BufReader::new(reader), doc_unit.chunk_boundary(), errors, cb),
+                    Err(err) => errors.report(None, err.into()),
+                }
+            },
+            ConlluSource::Zip(entries) => {
+                // Entries can be huge so each is streamed by one extraction thread in chunks
+                let threads = thread_config().extract;
+                let entry_queue = largest_first_queue(entries, compressed_size);
+                println!("Extracting zip entries using {} threads", threads);
+                for _ in 0..threads {
+                    let entry_queue_clone = entry_queue.clone();
+                    let cb_clone = cb.clone();
+                    scope.spawn(move |_| {
+                        for entry in entry_queue_clone {
+                            if errors.failed() {
+                                break;
+                            }
+                            let mut go_on = true;
+                            let mut offset = 0;
+                            split_entry_at_boundaries(buf, entry, doc_unit, errors, |chunk| {
+                                go_on = cb_clone(&chunk, ChunkStart { path: Some(entry_path(entry)), offset });
+                                offset += chunk.len();
+                                go_on
+                            });
+                            if !go_on {
+                                break;
+                            }
+                        }
+                    });
+                }
+            },
+        }
+    }
+}
+
+/// Streams a zip entry in chunks starting at `doc_unit` boundaries, reporting read errors to
+/// `errors`
+fn split_entry_at_boundaries<F: FnMut(Vec<u8>) -> bool>(buf: &[u8], entry: &MinEntry, doc_unit: DocUnit, errors: &ErrorLog, proc_chunk: F) {
+    let result = read_buf(buf, entry).and_then(|reader| {
+        split_stream_at_boundaries(reader, CHUNK_SIZE, doc_unit.chunk_boundary(), proc_chunk)
+    });
+    if let Err(err) = result {
+        errors.report(Some(entry_path(entry)), err.into());
+    }
+}
+
+/// Documents with a malformed token line are reported to `errors` and left out, the same as in
+/// `make_doc_bows`
+pub fn count_words(source: &ConlluSource, buf: &[u8], tokens: &ConlluTokens, doc_unit: DocUnit, errors: &ErrorLog) -> Result<(VocabBuilder, u64), Error> {
+    let counts = crossbeam::scope(|scope| {
+        let (snd, rcv) = unbounded();
+        source.for_each_chunk(scope, buf, doc_unit, errors, move |chunk, start| {
+            let mut vocab = VocabBuilder::new();
+            let mut doc_count = 0;
+            for_each_conllu_event(chunk, tokens, doc_unit, |event| {
+                match event {
+                    ConlluEvent::Token(tok) => vocab.add(tok),
+                    ConlluEvent::DocEnd(_) => doc_count += 1,
+                }
+            }, |line_offset, err| errors.report(start.path, at_offset(start.offset + line_offset, err)));
+            snd.send((vocab, doc_count)).is_ok()
+        });
+        parallel_reduce(rcv, |(mut acc, acc_docs), (other, other_docs)| {
+            acc.merge(other);
+            (acc, acc_docs + other_docs)
+        }).unwrap_or_else(|| (VocabBuilder::new(), 0))
+    }).unwrap();
+    errors.check()?;
+    Ok(counts)
+}
+
+/// Sends every document with its metadata, which is the id of documents with
+/// `# newdoc id = ...` as `newdoc_id`
+pub fn make_doc_bows<'env>(
+    scope: &Scope<'env>,
+    source: &'env ConlluSource,
+    buf: &'env [u8],
+    tokens: &'env ConlluTokens,
+    doc_unit: DocUnit,
+    vocab: &'env VocabMap,
+    errors: &'env ErrorLog
+) -> Receiver<(DocBow, DocMeta)>
+{
+    let (snd, rcv) = bounded(1024);
+    source.for_each_chunk(scope, buf, doc_unit, errors, move |chunk, start| {
+        let mut counts: BTreeMap<u32, u64> = BTreeMap::new();
+        let mut doc_words: u64 = 0;
+        let mut receiver_gone = false;
+        for_each_conllu_event(chunk, tokens, doc_unit, |event| {
+            match event {
+                ConlluEvent::Token(tok) => {
+                    let maybe_vocab_idx = vocab.get(tok);
+                    if let Some(vocab_idx) = maybe_vocab_idx {
+                        *counts.entry(*vocab_idx).or_insert(0) += 1;
+                        doc_words += 1;
+                    }
+                },
+                ConlluEvent::DocEnd(_) if receiver_gone => {},
+                ConlluEvent::DocEnd(id) => {
+                    let mut meta = DocMeta::new();
+                    if let Some(id) = id {
+                        meta.insert("newdoc_id".to_owned(), String::from_utf8_lossy(id).into_owned());
+                    }
+                    receiver_gone = snd.send(((doc_words, std::mem::take(&mut counts)), meta)).is_err();
+                    doc_words = 0;
+                }
+            }
+        }, |line_offset, err| errors.report(start.path, at_offset(start.offset + line_offset, err)));
+        !receiver_gone
+    });
+    rcv
+}
+
+pub struct ConlluCorpus {
+    mmap: Mmap,
+    source: ConlluSource,
+    tokens: ConlluTokens,
+    doc_unit: DocUnit,
+    errors: ErrorLog
+}
+
+impl ConlluCorpus {
+    pub fn new(path: &Path, token_key: TokenKey) -> Result<ConlluCorpus, Error> {
+        ConlluCorpus::new_with_options(path, token_key, &ConlluOptions::default())
+    }
+
+    /// Documents are delimited by `# newdoc` comments unless the file has none, in which case
+    /// `options.fallback_doc_unit` is used. The file can be compressed with gzip, xz, bzip2 or
+    /// zstd, in which case only its start is checked for `# newdoc` comments.
+    ///
+    /// The path can also be a zip archive of .conllu files, in which case each file is a document
+    /// unless it is split up further by `# newdoc` comments. The columns of the first file are
+    /// used for all of them.
+    pub fn new_with_options(path: &Path, token_key: TokenKey, options: &ConlluOptions) -> Result<ConlluCorpus, Error> {
+        let mmap = mmap_file(path)?;
+        let zip_entries = if is_zip(&mmap) {
+            let zip_reader: ZipArchive = ZipArchive::new(&mmap).map_err(|err| Error::in_file(path, err.into()))?;
+            let entries = filter_zip_entries(&zip_reader, is_conllu_file);
+            println!("{} entries in {}", entries.len(), path.display());
+            if entries.len() == 0 {
+                return Err(Error::in_file(path, Error::Format("No CoNLL-U files found".to_owned())));
+            }
+            Some(entries)
+        } else {
+            None
+        };
+        let compression = sniff_compression(&mmap);
+        let mut decompressed_head = Vec::new();
+        let head: &[u8] = if let Some(entries) = &zip_entries {
+            read_buf(&mmap, &entries[0])
+                .and_then(|reader| reader.take(CHUNK_SIZE as u64).read_to_end(&mut decompressed_head))
+                .map_err(|err| Error::in_file(&path.join(entry_path(&entries[0])), err.into()))?;
+            &decompressed_head
+        } else if compression == Compression::None {
+            &mmap
+        } else {
+            decompress_reader(&mmap[..], compression)
+                .and_then(|reader| reader.take(CHUNK_SIZE as u64).read_to_end(&mut decompressed_head))
+                .map_err(|err| Error::in_file(path, err.into()))?;
+            &decompressed_head
+        };
+        let column_names = parse_global_columns(head).unwrap_or_else(
+            || CONLLU_COLUMNS.iter().map(|name| (*name).to_owned()).collect()
+        );
+        let selection = options.columns.clone().unwrap_or_else(|| ColumnSelection::from_token_key(token_key));
+        let tokens = ConlluTokens::resolve(&selection, &column_names, options.multiword)
+            .map_err(|err| Error::in_file(path, err))?;
+        let fallback = options.fallback_doc_unit;
+        let doc_unit = if zip_entries.is_some() || next_boundary(head, 0, is_newdoc) < head.len() {
+            DocUnit::NewDoc
+        } else {
+            println!("No # newdoc comments in {}. Using {:?} as documents.", path.display(), fallback);
+            fallback
+        };
+        let source = match zip_entries {
+            Some(entries) => ConlluSource::Zip(entries),
+            None => ConlluSource::new(&mmap, doc_unit),
+        };
+        Ok(ConlluCorpus {
+            mmap,
+            source,
+            tokens,
+            doc_unit,
+            errors: ErrorLog::default(),
+        })
+    }
+
+    /// Reports unreadable zip entries and chunks to `errors` rather than failing on the first one
+    pub fn with_error_log(mut self, errors: ErrorLog) -> ConlluCorpus {
+        self.errors = errors;
+        self
+    }
+}
+
+impl Corpus for ConlluCorpus {
+    fn count_words(&self) -> Result<(VocabBuilder, u64), Error> {
+        count_words(&self.source, &self.mmap, &self.tokens, self.doc_unit, &self.errors)
+    }
+
+    fn gen_doc_bows<'env>(&'env self, scope: &Scope<'env>, vocab: &'env VocabMap) -> Receiver<Result<DocBow, Error>> {
+        let rcv = make_doc_bows(scope, &self.source, &self.mmap, &self.tokens, self.doc_unit, vocab, &self.errors);
+        self.errors.forward_checked_map(scope, rcv, |(doc, _meta)| doc)
+    }
+
+    fn gen_doc_bows_with_meta<'env>(&'env self, scope: &Scope<'env>, vocab: &'env VocabMap) -> Receiver<Result<(DocBow, DocMeta), Error>> {
+        let rcv = make_doc_bows(scope, &self.source, &self.mmap, &self.tokens, self.doc_unit, vocab, &self.errors);
+        self.errors.forward_checked(scope, rcv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Documents with and without ids, a CRLF document and tokens after the last blank line
+    const DOCS: &[u8] = b"# newdoc id = a\n1\tx\tx\n\n# newdoc\n1\ty\ty\n2\tz\tz\n\n#newdoc id=c\r\n1\tw\tw\r\n\r\n1\tv\tv";
+
+    fn stream_chunks(buf: &[u8], chunk_size: usize, is_boundary: fn(&[u8]) -> bool) -> Vec<Vec<u8>> {
+        let mut chunks = Vec::new();
+        split_stream_at_boundaries(buf, chunk_size, is_boundary, |chunk| {
+            chunks.push(chunk);
+            true
+        }).unwrap();
+        chunks
+    }
+
+    #[test]
+    fn split_at_boundaries_matches_stream() {
+        let boundaries: [fn(&[u8]) -> bool; 2] = [is_newdoc, is_blank];
+        for is_boundary in boundaries.iter() {
+            for chunk_size in 1..DOCS.len() + 2 {
+                let chunks = split_at_boundaries(DOCS, chunk_size, *is_boundary).into_iter()
+                    .map(|range| DOCS[range].to_vec())
+                    .collect::<Vec<_>>();
+                assert_eq!(chunks, stream_chunks(DOCS, chunk_size, *is_boundary), "chunk size {}", chunk_size);
+            }
+        }
+    }
+
+    #[test]
+    fn split_at_boundaries_at_every_newdoc() {
+        let chunks = split_at_boundaries(DOCS, 1, is_newdoc);
+        let starts = chunks.iter().map(|range| range.start).collect::<Vec<_>>();
+        assert_eq!(starts, vec![0, 23, 45]);
+        assert_eq!(chunks.last().unwrap().end, DOCS.len());
+    }
+
+    #[test]
+    fn parse_boundary_comment_forms() {
+        assert_eq!(parse_boundary_comment(b"# newdoc", b"newdoc"), Some(None));
+        assert_eq!(parse_boundary_comment(b"# newdoc id = x", b"newdoc"), Some(Some(&b"x"[..])));
+        assert_eq!(parse_boundary_comment(b"#newdoc id=x", b"newdoc"), Some(Some(&b"x"[..])));
+        assert_eq!(parse_boundary_comment(b"# newpar id = p1", b"newpar"), Some(Some(&b"p1"[..])));
+        assert_eq!(parse_boundary_comment(b"# newpar id = p1", b"newdoc"), None);
+        assert_eq!(parse_boundary_comment(b"# newdocs", b"newdoc"), None);
+        assert_eq!(parse_boundary_comment(b"# newdoc name = x", b"newdoc"), None);
+        assert_eq!(parse_boundary_comment(b"# text = newdoc", b"newdoc"), None);
+        assert_eq!(parse_boundary_comment(b"newdoc", b"newdoc"), None);
+    }
+
+    #[test]
+    fn parse_boundary_comment_crlf() {
+        assert_eq!(parse_boundary_comment(trim_cr(b"# newdoc id = x\r"), b"newdoc"), Some(Some(&b"x"[..])));
+        assert_eq!(parse_boundary_comment(b"# newdoc\r", b"newdoc"), Some(None));
+        assert!(is_newdoc(b"#newdoc id=x\r"));
+        assert!(is_blank(b"\r"));
+    }
+
+    /// Reads `buf` with `doc_unit`, returning the word forms of each document and the offsets of
+    /// malformed lines
+    fn read_docs(buf: &[u8], doc_unit: DocUnit) -> (Vec<Vec<String>>, Vec<usize>) {
+        let tokens = ConlluTokens::new(TokenKey::WordForm);
+        let mut docs = vec![Vec::new()];
+        let mut offsets = Vec::new();
+        for_each_conllu_event(buf, &tokens, doc_unit, |event| {
+            match event {
+                ConlluEvent::Token(tok) => docs.last_mut().unwrap().push(String::from_utf8_lossy(tok).into_owned()),
+                ConlluEvent::DocEnd(_) => docs.push(Vec::new()),
+            }
+        }, |offset, _err| offsets.push(offset));
+        docs.pop();
+        (docs, offsets)
+    }
+
+    #[test]
+    fn malformed_line_drops_its_document() {
+        let buf = b"# newdoc\n1\ta\n\n# newdoc\n1\tb\nX\tc\n2\td\n\n1\te\n\n# newdoc\n1\tf\n2\n";
+        let (docs, offsets) = read_docs(buf, DocUnit::NewDoc);
+        assert_eq!(docs, vec![vec!["a".to_owned()]]);
+        assert_eq!(offsets, vec![27, 54]);
+        let (docs, offsets) = read_docs(buf, DocUnit::Sentence);
+        assert_eq!(docs, vec![vec!["a".to_owned()], vec!["e".to_owned()]]);
+        assert_eq!(offsets, vec![27, 54]);
+    }
+
+    #[test]
+    fn next_boundary_from_any_position() {
+        let buf = b"a\n# newdoc\nb\n# newdoc\n";
+        assert_eq!(next_boundary(buf, 0, is_newdoc), 2);
+        assert_eq!(next_boundary(buf, 2, is_newdoc), 2);
+        // From within a line the search starts at the next line
+        assert_eq!(next_boundary(buf, 3, is_newdoc), 13);
+        assert_eq!(next_boundary(buf, 11, is_newdoc), 13);
+        assert_eq!(next_boundary(buf, 14, is_newdoc), buf.len());
+        assert_eq!(next_boundary(buf, buf.len() + 1, is_newdoc), buf.len());
+        assert_eq!(next_boundary(b"a\nb", 0, is_newdoc), 3);
     }
 }
@@ -1,17 +1,47 @@
 use std::path::Path;
 use std::str::FromStr;
 use crate::opensubs18::OpenSubs18Corpus;
-use crate::conllu::ConlluCorpus;
-use crate::vrt::VrtCorpus;
-use crate::types::Corpus;
+use crate::conllu::{ConlluCorpus, ConlluOptions};
+use crate::vrt::{VrtCorpus, VrtOptions};
+use crate::newscrawl::NewsCrawlDocs;
+use crate::tokenize::TokenizedCorpus;
+use crate::opus::{OpusCollection, open_opus_corpus};
+use crate::types::{Corpus, DocBow, DocMeta, TokenKey};
+use crate::vocab::{VocabBuilder, VocabMap};
+use crate::error::{self, Error, ErrorLog};
 use simple_error::SimpleError;
+use crossbeam_channel::{bounded, Receiver};
+use crossbeam::thread::Scope;
 
 
+/// Options for opening corpora. Options which don't apply to a corpus type are ignored.
+#[derive(Clone)]
+pub struct CorpusOptions {
+    pub token_key: TokenKey,
+    pub conllu: ConlluOptions,
+    pub vrt: VrtOptions,
+    /// Where entries which can't be read are reported. Fails on the first one by default.
+    pub errors: ErrorLog,
+}
+
+impl CorpusOptions {
+    pub fn new(token_key: TokenKey) -> CorpusOptions {
+        CorpusOptions {
+            token_key,
+            conllu: ConlluOptions::default(),
+            vrt: VrtOptions::default(),
+            errors: ErrorLog::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
 pub enum CorpusType {
     OpenSubtitles2018,
     NewsCrawlWMT18,
     Conllu,
-    Vrt
+    Vrt,
+    Opus(OpusCollection)
 }
 
 impl FromStr for CorpusType {
@@ -20,31 +50,122 @@ impl FromStr for CorpusType {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if s == "opensubs18" {
             Ok(CorpusType::OpenSubtitles2018)
-        } else if s == "newscrawl" {
+        } else if s == "newscrawl" || s == "wmt19" {
             Ok(CorpusType::NewsCrawlWMT18)
         } else if s == "conllu" {
             Ok(CorpusType::Conllu)
         } else if s == "vrt" {
             Ok(CorpusType::Vrt)
+        } else if s == "opus-finlex" {
+            Ok(CorpusType::Opus(OpusCollection::Finlex))
+        } else if s == "opus-europarl" {
+            Ok(CorpusType::Opus(OpusCollection::Europarl))
+        } else if s == "opus-opensub18" {
+            Ok(CorpusType::Opus(OpusCollection::OpenSubtitles2018))
         } else {
-            Err(SimpleError::new("Must be opensubs18 or newscrawl"))
+            Err(SimpleError::new("Must be opensubs18, newscrawl, conllu, vrt, opus-finlex, opus-europarl or opus-opensub18"))
         }
     }
 }
 
-pub fn get_corpus(corpus_path: &Path, corpus_type: CorpusType) -> Box<dyn Corpus> {
-    match corpus_type {
+pub fn get_corpus(corpus_path: &Path, corpus_type: CorpusType, options: &CorpusOptions) -> error::Result<Box<dyn Corpus>> {
+    let token_key = options.token_key;
+    let errors = options.errors.for_source(corpus_path);
+    Ok(match corpus_type {
         CorpusType::OpenSubtitles2018 => {
-            Box::new(OpenSubs18Corpus::new_from_path(corpus_path))
+            Box::new(OpenSubs18Corpus::new_from_path(corpus_path, token_key)?.with_error_log(errors))
         },
         CorpusType::NewsCrawlWMT18 => {
-            panic!("NewsCrawl not supported yet");
+            if token_key == TokenKey::Lemma {
+                return Err(Error::Config("NewsCrawl is not lemmatised so only word forms can be counted".to_owned()));
+            }
+            Box::new(TokenizedCorpus::new(NewsCrawlDocs::new(corpus_path)?.with_error_log(errors)))
         },
         CorpusType::Conllu => {
-            Box::new(ConlluCorpus::new(corpus_path))
+            Box::new(ConlluCorpus::new_with_options(corpus_path, token_key, &options.conllu)?.with_error_log(errors))
         },
         CorpusType::Vrt => {
-            Box::new(VrtCorpus::new_from_path(corpus_path))
+            Box::new(VrtCorpus::new_with_options(corpus_path, token_key, &options.vrt)?.with_error_log(errors))
+        },
+        CorpusType::Opus(collection) => {
+            Box::new(open_opus_corpus(corpus_path, collection, token_key)?.with_error_log(errors))
         },
+    })
+}
+
+/// Several corpora, possibly of different types, treated as a single corpus with a shared
+/// vocabulary and document count
+pub struct MultiCorpus {
+    corpora: Vec<Box<dyn Corpus>>
+}
+
+impl MultiCorpus {
+    pub fn new(corpora: Vec<Box<dyn Corpus>>) -> MultiCorpus {
+        MultiCorpus { corpora }
+    }
+}
+
+impl Corpus for MultiCorpus {
+    fn count_words(&self) -> error::Result<(VocabBuilder, u64)> {
+        let mut vocab = VocabBuilder::new();
+        let mut doc_count = 0;
+        for corpus in self.corpora.iter() {
+            let (corpus_vocab, corpus_doc_count) = corpus.count_words()?;
+            vocab.merge(corpus_vocab);
+            doc_count += corpus_doc_count;
+        }
+        Ok((vocab, doc_count))
+    }
+
+    fn gen_doc_bows<'env>(&'env self, scope: &Scope<'env>, vocab: &'env VocabMap) -> Receiver<error::Result<DocBow>> {
+        chain_doc_rcvs(scope, &self.corpora, move |corpus, scope| corpus.gen_doc_bows(scope, vocab))
+    }
+
+    fn gen_doc_bows_with_meta<'env>(&'env self, scope: &Scope<'env>, vocab: &'env VocabMap) -> Receiver<error::Result<(DocBow, DocMeta)>> {
+        chain_doc_rcvs(scope, &self.corpora, move |corpus, scope| corpus.gen_doc_bows_with_meta(scope, vocab))
+    }
+}
+
+/// Sends the documents of each corpus in turn. A corpus is only started once the one before it is
+/// done, so only one corpus' reader threads run at a time, like in count_words. Stops after the
+/// first error.
+fn chain_doc_rcvs<'env, T, F>(scope: &Scope<'env>, corpora: &'env [Box<dyn Corpus>], gen_docs: F) -> Receiver<error::Result<T>>
+    where T: Send + 'env, F: Fn(&'env dyn Corpus, &Scope<'env>) -> Receiver<error::Result<T>> + Send + 'env
+{
+    let (snd, rcv) = bounded(1024);
+    scope.spawn(move |scope| {
+        for corpus in corpora.iter() {
+            for doc in gen_docs(corpus.as_ref(), scope) {
+                let is_err = doc.is_err();
+                if snd.send(doc).is_err() || is_err {
+                    return;
+                }
+            }
+        }
+    });
+    rcv
+}
+
+/// Opens each path as a corpus of the corresponding type, joining them with MultiCorpus if there
+/// is more than one. A single corpus type is used for all paths.
+pub fn get_multi_corpus(corpus_paths: &[&Path], corpus_types: &[CorpusType], options: &CorpusOptions) -> error::Result<Box<dyn Corpus>> {
+    if corpus_paths.len() == 0 {
+        return Err(Error::Config("Need at least one input".to_owned()));
+    }
+    if corpus_types.len() != 1 && corpus_types.len() != corpus_paths.len() {
+        return Err(Error::Config(format!(
+            "Got {} corpus types for {} inputs. Need either one for all inputs or one per input.",
+            corpus_types.len(),
+            corpus_paths.len()
+        )));
+    }
+    let mut corpora = corpus_paths.iter().enumerate().map(|(idx, corpus_path)| {
+        let corpus_type = if corpus_types.len() == 1 { corpus_types[0] } else { corpus_types[idx] };
+        get_corpus(corpus_path, corpus_type, options)
+    }).collect::<error::Result<Vec<_>>>()?;
+    if corpora.len() == 1 {
+        Ok(corpora.pop().unwrap())
+    } else {
+        Ok(Box::new(MultiCorpus::new(corpora)))
     }
 }
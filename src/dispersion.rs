@@ -7,82 +7,260 @@
  *
  *
  * d the length of the corpus part in words
+ *
+ * Only documents where a occurs are visited, so the per document accumulators are sums which
+ * documents with v = 0 contribute nothing to.
  */
 
-pub fn kl_div_elem(v: u32, f: u32, d: u32, l: u32) -> f64 {
+use itertools::Itertools;
+use simple_error::SimpleError;
+use crate::types::DocBow;
+
+
+pub fn kl_div_elem(v: u64, f: u64, d: u64, l: u64) -> f64 {
     let v_by_f = (v as f64) / (f as f64);
     v_by_f * f64::log2(v_by_f * (l as f64) / (d as f64))
 }
 
 
-pub struct AccElement {
-    kl_div: f64,
-    occurences: u32,
-    sd_v_acc: f64,
-    sd_p_acc: f64,
+/// Carroll's D2 entropy term for one document: p * log2(p)
+pub fn carrols_elem(p: f64) -> f64 {
+    if p > 0.0 {
+        p * f64::log2(p)
+    } else {
+        0.0
+    }
 }
 
-impl AccElement {
-    pub fn zero() -> AccElement {
-        AccElement {
-            kl_div: 0.0f64,
-            occurences: 0u32,
-            sd_v_acc: 0.0f64,
-            sd_p_acc: 0.0f64,
-        }
-    }
+/// Gries' DP term for one document. Since documents without the word contribute |0 - s| = s
+/// and all s sum to 1 we subtract s here and add 1 back when normalising.
+pub fn dp_elem(v: u64, f: u64, d: u64, l: u64) -> f64 {
+    let s = d as f64 / l as f64;
+    ((v as f64 / f as f64) - s).abs() - s
 }
 
-pub fn acc_word(v: u32, f: u32, d: u32, l: u32, n: u32) -> AccElement {
-    // Independent of document, could be factored out
-    let mean_v = f as f64 / n as f64;
-    let p = v as f64 / d as f64;
-    AccElement {
-        kl_div: kl_div_elem(v, f, d, l),
-        occurences: (v > 0) as u32,
-        sd_v_acc: (v as f64 - mean_v).powi(2),
-        sd_p_acc: (p as f64 - mean_v).powi(2),
-    }
+/// Population standard deviation over all n documents from the sum and sum of squares
+fn sd_from_sums(sum: f64, sum_sq: f64, n: u64) -> f64 {
+    let mean = sum / n as f64;
+    (sum_sq / n as f64 - mean.powi(2)).max(0.0).sqrt()
 }
 
-pub fn reduce_word(left: &AccElement, right: &AccElement) -> AccElement {
-    AccElement {
-        kl_div: left.kl_div + right.kl_div,
-        occurences: left.occurences + right.occurences,
-        sd_v_acc: left.sd_v_acc + right.sd_v_acc,
-        sd_p_acc: left.sd_p_acc + right.sd_p_acc
-    }
+/// Whole corpus statistics available when finalising
+pub struct CorpusStats {
+    pub l: u64,
+    pub n: u64,
+    /// The length of the shortest document
+    pub min_d: u64,
 }
 
-pub struct FinalColumns {
-    pub kl_div: Vec<f64>,
-    pub idf: Vec<f64>,
-    pub vc: Vec<f64>,
-    //pub juillands_d: Vec<f64>,
-    //pub carrols_d: Vec<f64>,
-    pub zipf: Vec<f64>
+/// A dispersion measure which is computed in a single pass over the documents. Each word gets
+/// `num_accs` accumulators which start at zero and are updated by `acc` for every document the
+/// word occurs in. `finalise` then turns them into the value of the measure.
+pub struct Metric {
+    pub name: &'static str,
+    pub num_accs: usize,
+    pub acc: fn(accs: &mut [f64], v: u64, f: u64, d: u64, l: u64),
+    pub finalise: fn(accs: &[f64], f: u64, corpus: &CorpusStats) -> f64,
 }
 
-impl FinalColumns {
-    pub fn with_capacity(capacity: usize) -> FinalColumns {
-        FinalColumns {
-            kl_div: Vec::with_capacity(capacity),
-            idf: Vec::with_capacity(capacity),
-            vc: Vec::with_capacity(capacity),
-            //juillands_d: Vec::with_capacity(capacity),
-            //carrols_d: Vec::with_capacity(capacity),
-            zipf: Vec::with_capacity(capacity),
+pub static METRICS: &[Metric] = &[
+    Metric {
+        name: "kl_div",
+        num_accs: 1,
+        acc: |accs, v, f, d, l| accs[0] += kl_div_elem(v, f, d, l),
+        finalise: |accs, _f, _corpus| accs[0],
+    },
+    Metric {
+        name: "idf",
+        num_accs: 1,
+        acc: |accs, _v, _f, _d, _l| accs[0] += 1.0,
+        finalise: |accs, _f, corpus| (corpus.n as f64 / accs[0]).log10(),
+    },
+    Metric {
+        name: "vc",
+        // Sum of v^2
+        num_accs: 1,
+        acc: |accs, v, _f, _d, _l| accs[0] += (v as f64).powi(2),
+        finalise: |accs, f, corpus| {
+            let mean_v = f as f64 / corpus.n as f64;
+            sd_from_sums(f as f64, accs[0], corpus.n) / mean_v
+        },
+    },
+    Metric {
+        name: "juillands_d",
+        // Sum of p and sum of p^2
+        num_accs: 2,
+        acc: |accs, v, _f, d, _l| {
+            let p = v as f64 / d as f64;
+            accs[0] += p;
+            accs[1] += p.powi(2);
+        },
+        // D = 1 - V / sqrt(n - 1) where V is the variation coefficient of p. D is undefined for a
+        // single document, so it is NaN for n <= 1.
+        finalise: |accs, _f, corpus| {
+            if corpus.n <= 1 {
+                return f64::NAN;
+            }
+            let mean_p = accs[0] / corpus.n as f64;
+            let vc_p = sd_from_sums(accs[0], accs[1], corpus.n) / mean_p;
+            1.0 - vc_p / ((corpus.n - 1) as f64).sqrt()
+        },
+    },
+    Metric {
+        name: "carrols_d",
+        // Sum of p and sum of p * log2(p)
+        num_accs: 2,
+        acc: |accs, v, _f, d, _l| {
+            let p = v as f64 / d as f64;
+            accs[0] += p;
+            accs[1] += carrols_elem(p);
+        },
+        // D2 = (log2(sum p) - sum(p * log2(p)) / sum p) / log2(n)
+        finalise: |accs, _f, corpus| {
+            (accs[0].log2() - accs[1] / accs[0]) / (corpus.n as f64).log2()
+        },
+    },
+    Metric {
+        name: "dp",
+        num_accs: 1,
+        acc: |accs, v, f, d, l| accs[0] += dp_elem(v, f, d, l),
+        // DP = 0.5 * sum(|v / f - s|)
+        finalise: |accs, _f, _corpus| 0.5 * (1.0 + accs[0]),
+    },
+    Metric {
+        name: "dp_norm",
+        num_accs: 1,
+        acc: |accs, v, f, d, l| accs[0] += dp_elem(v, f, d, l),
+        // DPnorm = DP / (1 - min(s))
+        finalise: |accs, _f, corpus| {
+            0.5 * (1.0 + accs[0]) / (1.0 - corpus.min_d as f64 / corpus.l as f64)
+        },
+    },
+    Metric {
+        name: "zipf",
+        num_accs: 0,
+        acc: |_accs, _v, _f, _d, _l| {},
+        finalise: |_accs, f, corpus| ((f as f64 * 1000000000.0f64) / corpus.l as f64).log10(),
+    },
+];
+
+pub fn get_metric(name: &str) -> Option<&'static Metric> {
+    METRICS.iter().find(|metric| metric.name == name)
+}
+
+/// Parses a comma separated list of metric names
+pub fn parse_metrics(names: &str) -> Result<Vec<&'static Metric>, SimpleError> {
+    names.split(',').map(|name| {
+        get_metric(name.trim()).ok_or_else(|| SimpleError::new(format!(
+            "Unknown metric {}. Must be one of {}",
+            name,
+            METRICS.iter().map(|metric| metric.name).join(", ")
+        )))
+    }).collect()
+}
+
+/// Accumulators for a set of metrics for every word in the vocabulary
+pub struct MetricsAcc<'a> {
+    metrics: &'a [&'static Metric],
+    offsets: Vec<usize>,
+    stride: usize,
+    accs: Vec<f64>,
+    min_d: u64,
+}
+
+impl<'a> MetricsAcc<'a> {
+    pub fn new(metrics: &'a [&'static Metric], vocab_len: usize) -> MetricsAcc<'a> {
+        let mut offsets = Vec::with_capacity(metrics.len());
+        let mut stride = 0;
+        for metric in metrics {
+            offsets.push(stride);
+            stride += metric.num_accs;
+        }
+        MetricsAcc {
+            metrics,
+            offsets,
+            stride,
+            accs: vec![0.0f64; stride * vocab_len],
+            min_d: u64::MAX,
+        }
+    }
+
+    pub fn add_doc(&mut self, doc: &DocBow, word_counts: &[u64], l: u64) {
+        let (d, counts) = doc;
+        self.min_d = self.min_d.min(*d);
+        for (word_id, v) in counts.iter() {
+            let f = word_counts[*word_id as usize];
+            let word_accs = &mut self.accs[*word_id as usize * self.stride..];
+            for (metric, offset) in self.metrics.iter().zip(self.offsets.iter()) {
+                (metric.acc)(&mut word_accs[*offset..*offset + metric.num_accs], *v, f, *d, l);
+            }
         }
     }
+
+    /// Returns one column per metric, each with a value for every word
+    pub fn finalise(self, word_counts: &[u64], l: u64, n: u64) -> Vec<Vec<f64>> {
+        let corpus = CorpusStats { l, n, min_d: self.min_d };
+        self.metrics.iter().zip(self.offsets.iter()).map(|(metric, offset)| {
+            word_counts.iter().enumerate().map(|(word_id, f)| {
+                let start = word_id * self.stride + offset;
+                (metric.finalise)(&self.accs[start..start + metric.num_accs], *f, &corpus)
+            }).collect_vec()
+        }).collect_vec()
+    }
 }
 
-pub fn norm_word(cols: &mut FinalColumns, elem: AccElement, f: u32, l: u32, n: u32) {
-    // Independent of document, could be factored out
-    let mean_v = f as f64 / n as f64;
-    cols.kl_div.push(elem.kl_div);
-    cols.idf.push((n as f64 / elem.occurences as f64).log10());
-    cols.vc.push((elem.sd_v_acc / n as f64).sqrt() / mean_v);
-    //cols.juillands_d.push();
-    //cols.carrols_d.push();
-    cols.zipf.push(((f as f64 * 1000000000.0f64) / l as f64).log10());
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use super::*;
+
+    /// Runs all metrics over documents given as (d, v) where word 0 is a and word 1 fills up the
+    /// rest of each document, returning the value of each metric for a by name
+    fn metrics_for_a(docs: &[(u64, u64)]) -> BTreeMap<&'static str, f64> {
+        let metrics = METRICS.iter().collect_vec();
+        let f = docs.iter().map(|(_d, v)| v).sum::<u64>();
+        let l = docs.iter().map(|(d, _v)| d).sum::<u64>();
+        let word_counts = [f, l - f];
+        let mut acc = MetricsAcc::new(&metrics, word_counts.len());
+        for (d, v) in docs {
+            let mut counts = BTreeMap::new();
+            if *v > 0 {
+                counts.insert(0, *v);
+            }
+            counts.insert(1, d - v);
+            acc.add_doc(&(*d, counts), &word_counts, l);
+        }
+        let cols = acc.finalise(&word_counts, l, docs.len() as u64);
+        metrics.iter().zip(cols.iter()).map(|(metric, col)| (metric.name, col[0])).collect()
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 0.001, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn gries_2008_example() {
+        // The corpus at the top of this file
+        let metrics = metrics_for_a(&[(9, 1), (10, 2), (10, 3), (10, 4), (11, 5)]);
+        assert_close(metrics["juillands_d"], 0.785);
+        assert_close(metrics["carrols_d"], 0.938);
+        assert_close(metrics["dp"], 0.18);
+        assert_close(metrics["dp_norm"], 0.22);
+    }
+
+    #[test]
+    fn word_in_one_document() {
+        // The documents without a are never visited, so DP relies on adding their s back
+        let metrics = metrics_for_a(&[(10, 5), (10, 0), (10, 0), (10, 0), (10, 0)]);
+        assert_close(metrics["juillands_d"], 0.0);
+        assert_close(metrics["carrols_d"], 0.0);
+        assert_close(metrics["dp"], 0.8);
+        assert_close(metrics["dp_norm"], 1.0);
+    }
+
+    #[test]
+    fn juillands_d_single_document() {
+        let metrics = metrics_for_a(&[(10, 5)]);
+        assert!(metrics["juillands_d"].is_nan());
+    }
 }
@@ -0,0 +1,279 @@
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::{FromStr, Utf8Error};
+use std::sync::{Arc, Mutex};
+
+use arrow2::error::ArrowError;
+use crossbeam::thread::Scope;
+use crossbeam_channel::{bounded, Receiver};
+use piz::result::ZipError;
+use simple_error::SimpleError;
+use crate::json::write_json_string;
+
+
+/// Everything which can go wrong reading corpora and writing the results
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Zip(ZipError),
+    /// An XML error, with the byte position in the file where it was hit if known
+    Xml { position: Option<usize>, error: quick_xml::Error },
+    Utf8(Utf8Error),
+    Parquet(ArrowError),
+    /// Input which doesn't follow its format, e.g. a bad CoNLL-U token ID
+    Format(String),
+    /// Options which don't work with the input, e.g. asking for a column a file doesn't have
+    Config(String),
+    /// An error within a particular file or archive entry
+    InFile { path: PathBuf, error: Box<Error> },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn in_file(path: &Path, error: Error) -> Error {
+        Error::InFile { path: path.to_owned(), error: Box::new(error) }
+    }
+
+    pub fn xml_at(position: usize, error: quick_xml::Error) -> Error {
+        Error::Xml { position: Some(position), error }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Zip(err) => write!(f, "Zip error: {}", err),
+            Error::Xml { position: Some(position), error } => write!(f, "XML error at position {}: {}", position, error),
+            Error::Xml { position: None, error } => write!(f, "XML error: {}", error),
+            Error::Utf8(err) => write!(f, "Invalid UTF-8: {}", err),
+            Error::Parquet(err) => write!(f, "Parquet error: {}", err),
+            Error::Format(msg) => write!(f, "{}", msg),
+            Error::Config(msg) => write!(f, "{}", msg),
+            Error::InFile { path, error } => write!(f, "{}: {}", path.display(), error),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Zip(err) => Some(err),
+            Error::Xml { error, .. } => Some(error),
+            Error::Utf8(err) => Some(err),
+            Error::Parquet(err) => Some(err),
+            Error::InFile { error, .. } => Some(error.as_ref()),
+            Error::Format(_) | Error::Config(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<ZipError> for Error {
+    fn from(err: ZipError) -> Error {
+        Error::Zip(err)
+    }
+}
+
+impl From<quick_xml::Error> for Error {
+    fn from(err: quick_xml::Error) -> Error {
+        Error::Xml { position: None, error: err }
+    }
+}
+
+impl From<Utf8Error> for Error {
+    fn from(err: Utf8Error) -> Error {
+        Error::Utf8(err)
+    }
+}
+
+impl From<ArrowError> for Error {
+    fn from(err: ArrowError) -> Error {
+        Error::Parquet(err)
+    }
+}
+
+/// What to do when an archive entry or document can't be read
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OnError {
+    /// Stop reading and return the error
+    Fail,
+    /// Record it in the error report and carry on with everything else
+    Skip,
+}
+
+impl FromStr for OnError {
+    type Err = SimpleError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s == "fail" {
+            Ok(OnError::Fail)
+        } else if s == "skip" {
+            Ok(OnError::Skip)
+        } else {
+            Err(SimpleError::new("Must be skip or fail"))
+        }
+    }
+}
+
+/// An archive entry or part of a corpus which couldn't be read
+#[derive(Clone, Debug)]
+pub struct Skipped {
+    /// The corpus the entry is from
+    pub source: Option<String>,
+    /// The path of the entry within the corpus, e.g. within the zip
+    pub path: Option<String>,
+    pub reason: String,
+}
+
+/// Where corpus readers report entries they can't read. Clones share the same report.
+///
+/// Whole entries are skipped where they are read into memory before extraction (OpenSubtitles
+/// and OPUS). VRT entries, which are streamed, are cut short, keeping the documents before the
+/// error. In CoNLL-U only the document with a malformed token line is left out, while a read
+/// error cuts the rest of the file or entry short. The same documents are kept in every pass over
+/// the corpus.
+///
+/// When failing, the first error is kept until the pass over the corpus which hit it returns it
+/// from `check` and readers stop taking new entries once `failed` is set.
+#[derive(Clone, Debug)]
+pub struct ErrorLog {
+    on_error: OnError,
+    source: Option<String>,
+    skipped: Arc<Mutex<Vec<Skipped>>>,
+    fatal: Arc<Mutex<Option<Error>>>,
+}
+
+impl ErrorLog {
+    pub fn new(on_error: OnError) -> ErrorLog {
+        ErrorLog {
+            on_error,
+            source: None,
+            skipped: Arc::new(Mutex::new(Vec::new())),
+            fatal: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// A log sharing the same report which records `source` as the corpus of each entry
+    pub fn for_source(&self, source: &Path) -> ErrorLog {
+        ErrorLog { source: Some(source.to_string_lossy().into_owned()), ..self.clone() }
+    }
+
+    pub fn on_error(&self) -> OnError {
+        self.on_error
+    }
+
+    /// Reports that the entry at `path` couldn't be read, or some other part of the corpus if
+    /// `path` is None. Skipped entries are only recorded once even though most corpora are read
+    /// twice.
+    pub fn report(&self, path: Option<&Path>, error: Error) {
+        let location = match (&self.source, path) {
+            (Some(source), Some(path)) => Some(Path::new(source).join(path)),
+            (Some(source), None) => Some(PathBuf::from(source)),
+            (None, path) => path.map(Path::to_owned),
+        };
+        if self.on_error == OnError::Fail {
+            let mut fatal = self.fatal.lock().unwrap();
+            if fatal.is_none() {
+                *fatal = Some(match location {
+                    Some(location) => Error::in_file(&location, error),
+                    None => error,
+                });
+            }
+            return;
+        }
+        let path = path.map(|path| path.to_string_lossy().into_owned());
+        let reason = error.to_string();
+        let mut skipped = self.skipped.lock().unwrap();
+        if skipped.iter().any(|other| other.source == self.source && other.path == path && other.reason == reason) {
+            return;
+        }
+        match location {
+            Some(location) => eprintln!("Skipping {}: {}", location.display(), reason),
+            None => eprintln!("Skipping: {}", reason),
+        }
+        skipped.push(Skipped { source: self.source.clone(), path, reason });
+    }
+
+    /// Whether an error has been hit which should stop reading
+    pub fn failed(&self) -> bool {
+        self.fatal.lock().unwrap().is_some()
+    }
+
+    /// Returns the error which stopped reading, if any
+    pub fn check(&self) -> Result<()> {
+        match self.fatal.lock().unwrap().take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Forwards everything from `rcv` followed by the error which stopped reading, if any, once
+    /// everything sending to `rcv` is done. Forwarding stops if the returned receiver is dropped.
+    pub(crate) fn forward_checked<'env, T: Send + 'env>(&'env self, scope: &Scope<'env>, rcv: Receiver<T>) -> Receiver<Result<T>> {
+        self.forward_checked_map(scope, rcv, |item| item)
+    }
+
+    /// Like forward_checked but passes each item through `map`
+    pub(crate) fn forward_checked_map<'env, T: Send + 'env, U: Send + 'env>(
+        &'env self,
+        scope: &Scope<'env>,
+        rcv: Receiver<T>,
+        map: fn(T) -> U
+    ) -> Receiver<Result<U>> {
+        let (snd, checked_rcv) = bounded(1024);
+        scope.spawn(move |_| {
+            for item in rcv {
+                if snd.send(Ok(map(item))).is_err() {
+                    return;
+                }
+            }
+            if let Err(err) = self.check() {
+                let _ = snd.send(Err(err));
+            }
+        });
+        checked_rcv
+    }
+
+    pub fn skipped(&self) -> Vec<Skipped> {
+        self.skipped.lock().unwrap().clone()
+    }
+
+    /// Writes each skipped entry as a JSON object per line
+    pub fn write_report(&self, out_path: &Path) -> Result<()> {
+        let mut report = BufWriter::new(File::create(out_path)?);
+        for skipped in self.skipped.lock().unwrap().iter() {
+            report.write_all(b"{\"source\": ")?;
+            write_json_opt_string(&mut report, skipped.source.as_deref())?;
+            report.write_all(b", \"path\": ")?;
+            write_json_opt_string(&mut report, skipped.path.as_deref())?;
+            report.write_all(b", \"reason\": ")?;
+            write_json_string(&mut report, &skipped.reason)?;
+            report.write_all(b"}\n")?;
+        }
+        report.flush()?;
+        Ok(())
+    }
+}
+
+fn write_json_opt_string<W: Write>(writer: &mut W, string: Option<&str>) -> io::Result<()> {
+    match string {
+        Some(string) => write_json_string(writer, string),
+        None => writer.write_all(b"null"),
+    }
+}
+
+impl Default for ErrorLog {
+    fn default() -> ErrorLog {
+        ErrorLog::new(OnError::Fail)
+    }
+}
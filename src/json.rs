@@ -0,0 +1,19 @@
+use std::io::{self, Write};
+
+
+/// Writes `string` as a JSON string literal
+pub(crate) fn write_json_string<W: Write>(writer: &mut W, string: &str) -> io::Result<()> {
+    writer.write_all(b"\"")?;
+    for chr in string.chars() {
+        match chr {
+            '"' => write!(writer, "\\\"")?,
+            '\\' => write!(writer, "\\\\")?,
+            '\n' => write!(writer, "\\n")?,
+            '\r' => write!(writer, "\\r")?,
+            '\t' => write!(writer, "\\t")?,
+            chr if (chr as u32) < 0x20 => write!(writer, "\\u{:04x}", chr as u32)?,
+            chr => write!(writer, "{}", chr)?,
+        }
+    }
+    writer.write_all(b"\"")
+}
@@ -1,11 +1,18 @@
 pub mod termdocmat;
 pub mod opensubs18;
+pub mod opus;
 pub mod parquet2;
 pub mod dispersion;
 pub mod vrt;
 pub mod conllu;
+pub mod newscrawl;
+pub mod tokenize;
 pub mod types;
 pub mod parallel;
 pub mod vocab;
 pub mod corpus;
 pub mod zip;
+pub mod compression;
+pub mod error;
+mod json;
+pub use error::{Error, Result};
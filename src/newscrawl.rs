@@ -0,0 +1,138 @@
+use std::path::Path;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+
+use crossbeam_channel::bounded;
+use crossbeam::thread::Scope;
+use crate::tokenize::{DocIter, RawTextSource, TokenizedCorpus};
+use crate::parallel::thread_config;
+use crate::compression::open_decompressed;
+use crate::error::{Error, ErrorLog, Result};
+
+
+const LINE_BATCH_SIZE: usize = 256;
+
+
+pub fn open_newscrawl(path: &Path) -> io::Result<BufReader<Box<dyn Read + Send>>> {
+    open_decompressed(path)
+}
+
+fn trim_newline(line: &[u8]) -> &[u8] {
+    let mut end = line.len();
+    while end > 0 && (line[end - 1] == b'\n' || line[end - 1] == b'\r') {
+        end -= 1;
+    }
+    &line[..end]
+}
+
+/// Each line of the WMT document-level NewsCrawl is one document made up of the tab separated
+/// fields: date, base64 encoded sentence split text and base64 encoded original text. We take the
+/// sentence split text.
+pub fn decode_doc(line: &[u8]) -> Result<Vec<u8>> {
+    let field = trim_newline(line).split(|chr| *chr == b'\t').nth(1)
+        .ok_or_else(|| Error::Format("missing sentence split text field".to_owned()))?;
+    base64::decode(field).map_err(|err| Error::Format(format!("bad base64 in sentence split text: {}", err)))
+}
+
+/// Reads the (usually gzipped) file on one thread and hands batches of lines to the extraction threads which
+/// decode them. Each decoding thread calls `cb` once with an iterator over its decoded documents.
+/// Lines which can't be decoded are reported to `errors` and a read error ends the file.
+pub fn buffered_decode<'env, F>(
+    scope: &Scope<'env>,
+    path: &'env Path,
+    errors: &'env ErrorLog,
+    cb: F
+) -> ()
+    where F: Fn(&mut DocIter) -> () + Send + Clone + 'env
+{
+    let threads = thread_config().extract;
+    let (snd, rcv) = bounded::<Vec<(u64, Vec<u8>)>>(threads * 4);
+    let mut reader = match open_newscrawl(path) {
+        Ok(reader) => reader,
+        Err(err) => {
+            errors.report(None, err.into());
+            return;
+        }
+    };
+    scope.spawn(move |_| {
+        let mut batch = Vec::with_capacity(LINE_BATCH_SIZE);
+        let mut line_no = 0;
+        loop {
+            let mut line = Vec::new();
+            let read = match reader.read_until(b'\n', &mut line) {
+                Ok(read) => read,
+                Err(err) => {
+                    errors.report(None, io::Error::new(err.kind(), format!("{} after line {}", err, line_no)).into());
+                    break;
+                }
+            };
+            if read == 0 {
+                break;
+            }
+            line_no += 1;
+            batch.push((line_no, line));
+            if batch.len() == LINE_BATCH_SIZE {
+                if snd.send(batch).is_err() {
+                    return;
+                }
+                batch = Vec::with_capacity(LINE_BATCH_SIZE);
+            }
+        }
+        if !batch.is_empty() {
+            let _ = snd.send(batch);
+        }
+    });
+    println!("Decoding documents using {} threads", threads);
+    for _ in 0..threads {
+        let rcv_clone = rcv.clone();
+        let cb_clone = cb.clone();
+        scope.spawn(move |_| {
+            let mut docs = rcv_clone.iter().flatten().filter_map(|(line_no, line)| {
+                match decode_doc(&line) {
+                    Ok(doc) => Some(doc),
+                    Err(err) => {
+                        errors.report(None, Error::Format(format!("bad document at line {}: {}", line_no, err)));
+                        None
+                    }
+                }
+            });
+            cb_clone(&mut docs);
+        });
+    }
+}
+
+pub struct NewsCrawlDocs {
+    path: Box<Path>,
+    errors: ErrorLog
+}
+
+impl NewsCrawlDocs {
+    /// Checks that the file can be opened, although it is only read later
+    pub fn new(path: &Path) -> Result<NewsCrawlDocs> {
+        File::open(path).map_err(|err| Error::in_file(path, err.into()))?;
+        Ok(NewsCrawlDocs {
+            path: Box::from(path),
+            errors: ErrorLog::default(),
+        })
+    }
+
+    /// Reports lines which can't be decoded to `errors` rather than failing on the first one
+    pub fn with_error_log(mut self, errors: ErrorLog) -> NewsCrawlDocs {
+        self.errors = errors;
+        self
+    }
+}
+
+impl RawTextSource for NewsCrawlDocs {
+    fn for_each_doc_batch<'env, F>(&'env self, scope: &Scope<'env>, cb: F) -> ()
+        where F: Fn(&mut DocIter) -> () + Send + Clone + 'env
+    {
+        buffered_decode(scope, &self.path, &self.errors, cb)
+    }
+
+    fn errors(&self) -> &ErrorLog {
+        &self.errors
+    }
+}
+
+pub type NewsCrawlCorpus = TokenizedCorpus<NewsCrawlDocs>;
@@ -1,13 +1,14 @@
 use std::path::Path;
-use std::borrow::Borrow;
+use std::borrow::{Borrow, Cow};
 use std::fs::File;
 use std::io::{BufRead, Cursor};
 use std::vec::Vec;
 use std::collections::BTreeMap;
 use piz::ZipArchive;
 use quick_xml::events::Event;
+use quick_xml::escape::unescape;
 use memmap::Mmap;
-use crate::types::{Corpus, DocBow};
+use crate::types::{Corpus, DocBow, DocMeta, TokenKey};
 use crate::vocab::VocabMap;
 use piz::read::FileMetadata;
 use std::ffi::OsStr;
@@ -15,19 +16,47 @@ use crossbeam_channel::{unbounded, bounded, Receiver};
 use crossbeam::thread::Scope;
 use itertools::Itertools;
 use piz::read::read_direct;
-use crate::parallel::partition;
+use crate::parallel::{largest_first_queue, parallel_reduce, thread_config};
 use crate::vocab::VocabBuilder;
-use crate::zip::{MinEntries, open_piz, read_whole_file, UNZIP_READERS};
+use crate::error::{Error, ErrorLog, Result};
+use crate::zip::{MinEntry, MinEntries, open_piz, read_whole_file, compressed_size, entry_path};
 
 
 // Should probably be bigger than normal because deflate adds latency(?)
-static LEMMA_KEY: &[u8] = b"lemma";
+pub static LEMMA_KEY: &[u8] = b"lemma";
 
 
+/// The attribute of <w> holding the token, or None for the word form
+pub fn target_attr_key(token_key: TokenKey) -> Option<Box<[u8]>> {
+    match token_key {
+        TokenKey::WordForm => None,
+        TokenKey::Lemma => Some(Box::from(LEMMA_KEY)),
+    }
+}
+
 pub fn is_xml_file(entry: &FileMetadata) -> bool {
     entry.is_file() && entry.path.extension().and_then(OsStr::to_str) == Some("xml")
 }
 
+/// Gets the metadata of a subtitle from its path within the zip. OpenSubtitles paths look like
+/// OpenSubtitles/xml/fi/1999/120737/3290147.xml which is the release year, IMDb id and subtitle
+/// id. Other OPUS collections only get their path.
+pub fn entry_meta(path: &Path) -> DocMeta {
+    let mut meta = DocMeta::new();
+    meta.insert("path".to_owned(), path.to_string_lossy().into_owned());
+    let is_number = |comp: &&str| !comp.is_empty() && comp.bytes().all(|chr| chr.is_ascii_digit());
+    let comps = path.iter().rev().take(3).map(|comp| comp.to_str().unwrap_or("")).collect_vec();
+    if let [file_name, imdb_id, year] = comps[..] {
+        let subtitle_id = file_name.strip_suffix(".xml").unwrap_or(file_name);
+        if is_number(&year) && year.len() == 4 && is_number(&imdb_id) && is_number(&subtitle_id) {
+            meta.insert("year".to_owned(), year.to_owned());
+            meta.insert("imdb_id".to_owned(), imdb_id.to_owned());
+            meta.insert("subtitle_id".to_owned(), subtitle_id.to_owned());
+        }
+    }
+    meta
+}
+
 /*
 pub fn iter_subtitles_enumerated<'a, 'b>(zip_reader: &'a ZipArchive<'b>) -> impl ParallelIterator<Item=(usize, quick_xml::Reader<BufReader<Box<dyn std::io::Read + Send + 'b>>>)> + 'a {
     zip_reader
@@ -64,31 +93,142 @@ pub fn iter_subtitles_whole_file<'a>(xml_entries: &'a MinEntries, mmap: &'a Mmap
 
 */
 
+/// Finds the still escaped value of the attribute `key` among the raw attributes of a tag. OPUS
+/// puts many attributes on each <w> and unlike quick-xml's attribute iterator this doesn't
+/// build anything for the other attributes. Attributes without `=` or a quoted value are errors,
+/// as they are for quick-xml.
+pub fn find_raw_attr<'a>(attrs: &'a [u8], key: &[u8]) -> quick_xml::Result<Option<&'a [u8]>> {
+    let skip_whitespace = |mut pos: usize| {
+        while pos < attrs.len() && attrs[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        pos
+    };
+    let mut pos = skip_whitespace(0);
+    while pos < attrs.len() {
+        let key_start = pos;
+        while pos < attrs.len() && attrs[pos] != b'=' && !attrs[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        let key_end = pos;
+        pos = skip_whitespace(pos);
+        if attrs.get(pos) != Some(&b'=') {
+            return Err(quick_xml::Error::NoEqAfterName(pos));
+        }
+        pos = skip_whitespace(pos + 1);
+        let quote = match attrs.get(pos) {
+            Some(quote) if *quote == b'"' || *quote == b'\'' => *quote,
+            _ => return Err(quick_xml::Error::UnquotedValue(pos)),
+        };
+        let value_start = pos + 1;
+        let value_end = match attrs[value_start..].iter().position(|chr| *chr == quote) {
+            Some(len) => value_start + len,
+            None => return Err(quick_xml::Error::UnquotedValue(pos)),
+        };
+        if &attrs[key_start..key_end] == key {
+            return Ok(Some(&attrs[value_start..value_end]));
+        }
+        pos = skip_whitespace(value_end + 1);
+    }
+    Ok(None)
+}
+
+/// Unescapes XML text, without any copying in the usual case that there is nothing to unescape
+pub fn unescape_if_needed(raw: &[u8]) -> quick_xml::Result<Cow<'_, [u8]>> {
+    if raw.contains(&b'&') {
+        unescape(raw).map_err(quick_xml::Error::EscapeError)
+    } else {
+        Ok(Cow::Borrowed(raw))
+    }
+}
+
+/// Gets the token from <w> elements. If `target_attr_key` is given the token is the value of that
+/// attribute, otherwise it is the word form within the element.
 pub fn next_opensubs_doc_token<R, F: FnMut(&[u8]) -> R, BR: BufRead>(
     buf: &mut Vec::<u8>,
     reader: &mut quick_xml::Reader<BR>,
-    target_attr_key: &[u8],
+    target_attr_key: Option<&[u8]>,
     mut proc_token: F
-) -> Option<R> {
+) -> quick_xml::Result<Option<R>> {
+    let mut in_w = false;
     loop {
         match reader.read_event(buf) {
             Ok(Event::Start(ref e)) => {
                 match e.name() {
                     b"w" => {
-                        for attr in e.attributes().with_checks(false) {
-                            let unwrapped_attr = attr.unwrap();
-                            if unwrapped_attr.key == target_attr_key {
-                                // XXX: Could just use the following if we were able to work with [u8]
-                                let lemma_cow = unwrapped_attr.unescaped_value().unwrap();
-                                return Some(proc_token(lemma_cow.borrow()));
-                            }
+                        match target_attr_key {
+                            Some(target_attr_key) => {
+                                if let Some(lemma) = find_raw_attr(e.attributes_raw(), target_attr_key)? {
+                                    return Ok(Some(proc_token(unescape_if_needed(lemma)?.borrow())));
+                                }
+                            },
+                            None => in_w = true,
                         }
                     }
                     _ => (),
                 }
             },
-            Ok(Event::Eof) => return None,
-            Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
+            Ok(Event::Text(ref e)) => {
+                if in_w {
+                    return Ok(Some(proc_token(unescape_if_needed(e.escaped())?.borrow())));
+                }
+            },
+            Ok(Event::End(_)) => in_w = false,
+            Ok(Event::Eof) => return Ok(None),
+            Err(e) => return Err(e),
+            _ => (),
+        }
+        buf.clear();
+    }
+}
+
+/// Calls `proc_event` with Some(token) for every token in the file and with None at the end of
+/// every document. When `doc_elem` is None the whole file is a single document, otherwise each
+/// `doc_elem` element starts a new document.
+pub fn for_each_opensubs_doc_token<F: FnMut(Option<&[u8]>), BR: BufRead>(
+    buf: &mut Vec::<u8>,
+    reader: &mut quick_xml::Reader<BR>,
+    target_attr_key: Option<&[u8]>,
+    doc_elem: Option<&[u8]>,
+    mut proc_event: F
+) -> quick_xml::Result<()> {
+    let mut in_doc = doc_elem.is_none();
+    let mut in_w = false;
+    loop {
+        match reader.read_event(buf) {
+            Ok(Event::Start(ref e)) => {
+                if Some(e.name()) == doc_elem {
+                    if in_doc {
+                        proc_event(None);
+                    }
+                    in_doc = true;
+                } else if e.name() == b"w" {
+                    match target_attr_key {
+                        Some(target_attr_key) => {
+                            if let Some(lemma) = find_raw_attr(e.attributes_raw(), target_attr_key)? {
+                                in_doc = true;
+                                proc_event(Some(unescape_if_needed(lemma)?.borrow()));
+                            }
+                        },
+                        None => in_w = true,
+                    }
+                }
+            },
+            Ok(Event::Text(ref e)) => {
+                if in_w {
+                    in_doc = true;
+                    proc_event(Some(unescape_if_needed(e.escaped())?.borrow()));
+                    in_w = false;
+                }
+            },
+            Ok(Event::End(_)) => in_w = false,
+            Ok(Event::Eof) => {
+                if in_doc {
+                    proc_event(None);
+                }
+                return Ok(());
+            },
+            Err(e) => return Err(e),
             _ => (),
         }
         buf.clear();
@@ -105,25 +245,63 @@ pub fn iter_flat_tokens<'a>(xml_entries: &'a MinEntries, mmap: &'a Mmap, target_
 */
 
 
+fn xml_error_at<BR: BufRead>(reader: &quick_xml::Reader<BR>, err: quick_xml::Error) -> Error {
+    Error::xml_at(reader.buffer_position(), err)
+}
+
+/// Calls `cb` on an XML reader for each entry using the extraction threads, each of which stops
+/// once `cb` returns false
 pub fn buffered_extract<'env, F>(
     scope: &Scope<'env>,
     xml_entries: &'env MinEntries,
     mmap: &'env Mmap,
+    errors: &'env ErrorLog,
     cb: F
 ) -> ()
-    where F: Fn(quick_xml::Reader<Cursor<Box<[u8]>>>) -> () + Send + Clone + 'env
+    where F: Fn(&'env MinEntry, quick_xml::Reader<Cursor<Box<[u8]>>>, &mut Vec<u8>) -> bool + Send + Clone + 'env
 {
-    let entries_partitioned = partition(
-        &xml_entries,
-        UNZIP_READERS
+    let threads = thread_config();
+    let (snd, rcv) = bounded(threads.extract * 2);
+    let entry_queue = largest_first_queue(xml_entries, compressed_size);
+    println!(
+        "Extracting zip entries using {} decompression threads and {} extraction threads",
+        threads.decompress,
+        threads.extract
     );
-    println!("Extracting zip entries using {} threads", UNZIP_READERS);
-    for entry_slice in entries_partitioned {
+    for _ in 0..threads.decompress {
+        let entry_queue_clone = entry_queue.clone();
+        let snd_clone = snd.clone();
+        scope.spawn(move |_| {
+            for entry in entry_queue_clone {
+                if errors.failed() {
+                    break;
+                }
+                match read_whole_file(mmap, entry) {
+                    Ok(contents) => {
+                        // The extraction threads have all stopped
+                        if snd_clone.send((entry, contents)).is_err() {
+                            break;
+                        }
+                    },
+                    Err(err) => errors.report(Some(entry_path(entry)), err.into()),
+                }
+            }
+        });
+    }
+    for _ in 0..threads.extract {
+        let rcv_clone = rcv.clone();
         let cb_clone = cb.clone();
         scope.spawn(move |_| {
-            for entry in entry_slice {
-                let contents = read_whole_file(mmap, entry);
-                cb_clone(quick_xml::Reader::from_reader(contents));
+            // The event buffer is reused for every entry this thread reads
+            let mut xml_read_buf = Vec::<u8>::new();
+            for (entry, contents) in rcv_clone {
+                let mut reader = quick_xml::Reader::from_reader(contents);
+                // The OPUS XML is machine generated so we don't need to check it
+                reader.check_end_names(false);
+                xml_read_buf.clear();
+                if !cb_clone(entry, reader, &mut xml_read_buf) {
+                    break;
+                }
             }
         });
     }
@@ -132,28 +310,43 @@ pub fn buffered_extract<'env, F>(
 pub fn count_words<'env, 'a>(
     xml_entries: &'env MinEntries,
     mmap: &'env Mmap,
-    target_attr_key: &'a [u8],
-) -> VocabBuilder {
-    crossbeam::scope(|scope| {
+    target_attr_key: Option<&'a [u8]>,
+    doc_elem: Option<&'a [u8]>,
+    errors: &'env ErrorLog
+) -> Result<(VocabBuilder, u64)> {
+    let counts = crossbeam::scope(|scope| {
         let (snd, rcv) = unbounded();
-        buffered_extract(scope, xml_entries, mmap, move |reader| {
+        buffered_extract(scope, xml_entries, mmap, errors, move |entry, mut reader, xml_read_buf| {
             let mut vocab = VocabBuilder::new();
-            let mut doc = OpenSubsDoc::new(reader, target_attr_key);
-            doc.next_token(|t| vocab.add(t));
-            snd.send(vocab).unwrap()
+            let mut doc_count = 0;
+            let result = for_each_opensubs_doc_token(xml_read_buf, &mut reader, target_attr_key, doc_elem, |tok| {
+                match tok {
+                    Some(tok) => vocab.add(tok),
+                    None => doc_count += 1,
+                }
+            });
+            match result {
+                Ok(()) => snd.send((vocab, doc_count)).is_ok(),
+                Err(err) => {
+                    errors.report(Some(entry_path(entry)), xml_error_at(&reader, err));
+                    true
+                },
+            }
         });
-        rcv.iter().reduce(|mut acc, other| {
+        parallel_reduce(rcv, |(mut acc, acc_docs), (other, other_docs)| {
             acc.merge(other);
-            acc
-        }).unwrap()
-    }).unwrap()
+            (acc, acc_docs + other_docs)
+        }).unwrap_or_else(|| (VocabBuilder::new(), 0))
+    }).unwrap();
+    errors.check()?;
+    Ok(counts)
 }
 
-pub fn xml_to_doc_bow<'a>(mut reader: quick_xml::Reader<impl BufRead>, vocab: &'a VocabMap, target_attr_key: &'a [u8]) -> DocBow {
+pub fn xml_to_doc_bow<'a>(mut reader: quick_xml::Reader<impl BufRead>, vocab: &'a VocabMap, target_attr_key: Option<&'a [u8]>) -> quick_xml::Result<DocBow> {
     let mut xml_read_buf = Vec::<u8>::new();
-    let mut counts: BTreeMap<u32, u32> = BTreeMap::new();
+    let mut counts: BTreeMap<u32, u64> = BTreeMap::new();
     // XXX: Could have some kind of pool for these
-    let mut doc_words: u32 = 0;
+    let mut doc_words: u64 = 0;
     loop {
         let got_some = next_opensubs_doc_token(&mut xml_read_buf, &mut reader, target_attr_key, |lemma| {
             let maybe_vocab_idx = vocab.get(lemma);
@@ -161,12 +354,40 @@ pub fn xml_to_doc_bow<'a>(mut reader: quick_xml::Reader<impl BufRead>, vocab: &'
                 *counts.entry(*vocab_idx).or_insert(0) += 1;
                 doc_words += 1;
             }
-        });
+        })?;
         if got_some == None {
             break;
         }
     }
-    (doc_words, counts)
+    Ok((doc_words, counts))
+}
+
+/// Like xml_to_doc_bow but splits the file into a document per `doc_elem` element
+pub fn xml_to_doc_bows<'a, F: FnMut(DocBow)>(
+    xml_read_buf: &mut Vec<u8>,
+    reader: &mut quick_xml::Reader<impl BufRead>,
+    vocab: &'a VocabMap,
+    target_attr_key: Option<&'a [u8]>,
+    doc_elem: Option<&'a [u8]>,
+    mut proc_doc: F
+) -> quick_xml::Result<()> {
+    let mut counts: BTreeMap<u32, u64> = BTreeMap::new();
+    let mut doc_words: u64 = 0;
+    for_each_opensubs_doc_token(xml_read_buf, reader, target_attr_key, doc_elem, |tok| {
+        match tok {
+            Some(lemma) => {
+                let maybe_vocab_idx = vocab.get(lemma);
+                if let Some(vocab_idx) = maybe_vocab_idx {
+                    *counts.entry(*vocab_idx).or_insert(0) += 1;
+                    doc_words += 1;
+                }
+            },
+            None => {
+                proc_doc((doc_words, std::mem::take(&mut counts)));
+                doc_words = 0;
+            }
+        }
+    })
 }
 
 /*
@@ -175,17 +396,33 @@ pub fn iter_doc_bows<'a>(xml_entries: &'a MinEntries, mmap: &'a Mmap, vocab: &'a
 }
 */
 
+/// Sends every document with its metadata. The documents of each entry are only sent once the
+/// whole entry has been read so that entries with errors can be skipped entirely.
 pub fn iter_doc_bows_buf<'env, 'a>(
     scope: &Scope<'env>,
     xml_entries: &'env MinEntries,
     mmap: &'env Mmap,
     vocab: &'env VocabMap,
-    target_attr_key: &'env [u8]
-) -> Receiver<DocBow>
+    target_attr_key: Option<&'env [u8]>,
+    doc_elem: Option<&'env [u8]>,
+    errors: &'env ErrorLog
+) -> Receiver<(DocBow, DocMeta)>
 {
     let (snd, rcv) = bounded(1024);
-    buffered_extract(scope, xml_entries, mmap, move |reader| {
-        snd.send(xml_to_doc_bow(reader, vocab, target_attr_key)).unwrap();
+    buffered_extract(scope, xml_entries, mmap, errors, move |entry, mut reader, xml_read_buf| {
+        let mut docs = Vec::new();
+        match xml_to_doc_bows(xml_read_buf, &mut reader, vocab, target_attr_key, doc_elem, |doc| docs.push(doc)) {
+            Ok(()) => {
+                let meta = entry_meta(entry_path(entry));
+                for doc in docs {
+                    if snd.send((doc, meta.clone())).is_err() {
+                        return false;
+                    }
+                }
+            },
+            Err(err) => errors.report(Some(entry_path(entry)), xml_error_at(&reader, err)),
+        }
+        true
     });
     rcv
 }
@@ -193,29 +430,29 @@ pub fn iter_doc_bows_buf<'env, 'a>(
 pub struct OpenSubsDoc<'b, BR: BufRead> {
     buf: Vec::<u8>,
     reader: quick_xml::Reader<BR>,
-    target_attr_key: &'b [u8]
+    target_attr_key: Option<&'b [u8]>
 }
 
 impl<'b, BR: BufRead> OpenSubsDoc<'b, BR> {
-    pub fn new(reader: quick_xml::Reader<BR>, target_attr_key: &'b [u8]) -> OpenSubsDoc<'b, BR> {
+    pub fn new(reader: quick_xml::Reader<BR>, target_attr_key: Option<&'b [u8]>) -> OpenSubsDoc<'b, BR> {
         OpenSubsDoc { buf: Vec::new(), reader, target_attr_key }
     }
 
-    pub fn new_with_buf(buf: Vec::<u8>, reader: quick_xml::Reader<BR>, target_attr_key: &'b [u8]) -> OpenSubsDoc<'b, BR> {
+    pub fn new_with_buf(buf: Vec::<u8>, reader: quick_xml::Reader<BR>, target_attr_key: Option<&'b [u8]>) -> OpenSubsDoc<'b, BR> {
         OpenSubsDoc { buf, reader, target_attr_key }
     }
 
-    pub fn next_token<R, F: FnMut(&[u8]) -> R>(&mut self, proc_token: F) -> Option<R> {
+    pub fn next_token<R, F: FnMut(&[u8]) -> R>(&mut self, proc_token: F) -> quick_xml::Result<Option<R>> {
         next_opensubs_doc_token(&mut self.buf, &mut self.reader, self.target_attr_key, proc_token)
     }
 }
 
 
 impl<'b, BR: BufRead> Iterator for OpenSubsDoc<'b, BR> {
-    type Item = Box<[u8]>;
+    type Item = quick_xml::Result<Box<[u8]>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.next_token(|x| x.to_owned().into_boxed_slice())
+        self.next_token(|x| x.to_owned().into_boxed_slice()).transpose()
     }
 }
 
@@ -223,37 +460,67 @@ impl<'b, BR: BufRead> Iterator for OpenSubsDoc<'b, BR> {
 pub struct OpenSubs18Corpus {
     xml_entries: MinEntries,
     mmap: Mmap,
-    target_attr_key: Box<[u8]>
+    target_attr_key: Option<Box<[u8]>>,
+    doc_elem: Option<Box<[u8]>>,
+    errors: ErrorLog
 }
 
 impl OpenSubs18Corpus {
     pub fn new(
         xml_entries: MinEntries,
         mmap: Mmap,
-        target_attr_key: Box<[u8]>
+        target_attr_key: Option<Box<[u8]>>
+    ) -> OpenSubs18Corpus {
+        OpenSubs18Corpus::new_with_doc_elem(xml_entries, mmap, target_attr_key, None)
+    }
+
+    /// Each `doc_elem` element within each XML file is treated as a separate document rather
+    /// than the file as a whole
+    pub fn new_with_doc_elem(
+        xml_entries: MinEntries,
+        mmap: Mmap,
+        target_attr_key: Option<Box<[u8]>>,
+        doc_elem: Option<Box<[u8]>>
     ) -> OpenSubs18Corpus {
         OpenSubs18Corpus {
-            xml_entries, mmap, target_attr_key
+            xml_entries, mmap, target_attr_key, doc_elem, errors: ErrorLog::default()
         }
     }
 
-    pub fn new_from_path(path: &Path) -> OpenSubs18Corpus {
-        let (mmap, entries) = open_piz(path, is_xml_file);
-        OpenSubs18Corpus::new(
+    /// Reports unreadable entries to `errors` rather than failing on the first one
+    pub fn with_error_log(mut self, errors: ErrorLog) -> OpenSubs18Corpus {
+        self.errors = errors;
+        self
+    }
+
+    pub fn new_from_path(path: &Path, token_key: TokenKey) -> Result<OpenSubs18Corpus> {
+        let (mmap, entries) = open_piz(path, is_xml_file)?;
+        Ok(OpenSubs18Corpus::new(
             entries,
             mmap,
-            Box::from(LEMMA_KEY)
-        )
+            target_attr_key(token_key)
+        ))
     }
 }
 
 impl Corpus for OpenSubs18Corpus {
-    fn count_words(&self) -> (VocabBuilder, u32) {
-        let vocab = count_words(&self.xml_entries, &self.mmap, &self.target_attr_key);
-        (vocab, self.xml_entries.len() as u32)
+    fn count_words(&self) -> Result<(VocabBuilder, u64)> {
+        count_words(&self.xml_entries, &self.mmap, self.target_attr_key.as_deref(), self.doc_elem.as_deref(), &self.errors)
+    }
+
+    fn gen_doc_bows<'env>(&'env self, scope: &Scope<'env>, vocab: &'env VocabMap) -> Receiver<Result<DocBow>> {
+        let rcv = iter_doc_bows_buf(
+            scope, &self.xml_entries, &self.mmap, vocab, self.target_attr_key.as_deref(), self.doc_elem.as_deref(),
+            &self.errors
+        );
+        self.errors.forward_checked_map(scope, rcv, |(doc, _meta)| doc)
     }
 
-    fn gen_doc_bows<'env>(&'env self, scope: &Scope<'env>, vocab: &'env VocabMap) -> Receiver<DocBow> {
-        iter_doc_bows_buf(scope, &self.xml_entries, &self.mmap, vocab, &self.target_attr_key)
+    fn gen_doc_bows_with_meta<'env>(&'env self, scope: &Scope<'env>, vocab: &'env VocabMap) -> Receiver<Result<(DocBow, DocMeta)>> {
+        let rcv = iter_doc_bows_buf(
+            scope, &self.xml_entries, &self.mmap, vocab, self.target_attr_key.as_deref(), self.doc_elem.as_deref(),
+            &self.errors
+        );
+        self.errors.forward_checked(scope, rcv)
     }
 }
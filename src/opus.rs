@@ -0,0 +1,99 @@
+use std::path::Path;
+use std::ffi::OsStr;
+
+use piz::ZipArchive;
+use piz::read::FileMetadata;
+use crate::opensubs18::{OpenSubs18Corpus, is_xml_file, target_attr_key};
+use crate::types::TokenKey;
+use crate::error::{Error, Result};
+use crate::zip::{filter_zip_entries, mmap_file};
+
+
+/// The OPUS collections we know how to read from the parsed zip downloads, e.g.
+/// https://opus.nlpl.eu/download.php?f=Europarl/v8/parsed/fi.zip
+#[derive(Clone, Copy)]
+pub enum OpusCollection {
+    Finlex,
+    Europarl,
+    OpenSubtitles2018,
+}
+
+fn is_collection_xml_file(entry: &FileMetadata, dir_name: &str) -> bool {
+    is_xml_file(entry) && entry.path.iter().any(|comp| comp == OsStr::new(dir_name))
+}
+
+fn is_finlex_file(entry: &FileMetadata) -> bool {
+    is_collection_xml_file(entry, OpusCollection::Finlex.dir_name())
+}
+
+fn is_europarl_file(entry: &FileMetadata) -> bool {
+    is_collection_xml_file(entry, OpusCollection::Europarl.dir_name())
+}
+
+fn is_opensubs_file(entry: &FileMetadata) -> bool {
+    is_collection_xml_file(entry, OpusCollection::OpenSubtitles2018.dir_name())
+}
+
+impl OpusCollection {
+    /// The top level directory of the collection within the zip, e.g. Europarl/parsed/fi/*.xml
+    pub fn dir_name(&self) -> &'static str {
+        match self {
+            OpusCollection::Finlex => "Finlex",
+            OpusCollection::Europarl => "Europarl",
+            OpusCollection::OpenSubtitles2018 => "OpenSubtitles",
+        }
+    }
+
+    pub fn entry_pred(&self) -> fn(&FileMetadata) -> bool {
+        match self {
+            OpusCollection::Finlex => is_finlex_file,
+            OpusCollection::Europarl => is_europarl_file,
+            OpusCollection::OpenSubtitles2018 => is_opensubs_file,
+        }
+    }
+
+    /// The element which delimits documents within each XML file. None means the whole file is a
+    /// document: one statute for Finlex and one movie for OpenSubtitles. Europarl files contain a
+    /// whole sitting, so we split them into chapters (agenda items).
+    pub fn doc_elem(&self) -> Option<&'static [u8]> {
+        match self {
+            OpusCollection::Finlex => None,
+            OpusCollection::Europarl => Some(b"CHAPTER"),
+            OpusCollection::OpenSubtitles2018 => None,
+        }
+    }
+
+    /// All the parsed releases are tagged with UDPipe which puts the lemma in the `lemma`
+    /// attribute of each <w> and leaves the word form as its text
+    pub fn target_attr_key(&self, token_key: TokenKey) -> Option<Box<[u8]>> {
+        target_attr_key(token_key)
+    }
+}
+
+pub fn open_opus_corpus(path: &Path, collection: OpusCollection, token_key: TokenKey) -> Result<OpenSubs18Corpus> {
+    let mmap = mmap_file(path)?;
+    let entries = {
+        let zip_reader: ZipArchive = ZipArchive::new(&mmap).map_err(|err| Error::in_file(path, err.into()))?;
+        let entries = filter_zip_entries(&zip_reader, collection.entry_pred());
+        if entries.len() == 0 {
+            eprintln!(
+                "No XML files found under {}/ in {}. Using all XML files instead.",
+                collection.dir_name(),
+                path.display()
+            );
+            filter_zip_entries(&zip_reader, is_xml_file)
+        } else {
+            entries
+        }
+    };
+    println!("{} entries in {}", entries.len(), path.display());
+    if entries.len() == 0 {
+        return Err(Error::in_file(path, Error::Format("No XML files found".to_owned())));
+    }
+    Ok(OpenSubs18Corpus::new_with_doc_elem(
+        entries,
+        mmap,
+        collection.target_attr_key(token_key),
+        collection.doc_elem().map(Box::from)
+    ))
+}
@@ -1,6 +1,85 @@
-use std::slice::Chunks;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+use itertools::Itertools;
+use crossbeam_channel::{unbounded, Receiver};
 
-pub fn partition<'a, T>(slice: &'a [T], num_slices: usize) -> Chunks<'a, T> {
-    slice.chunks((slice.len() + num_slices - 1) / num_slices)
+
+static EXTRACT_THREADS: AtomicUsize = AtomicUsize::new(4);
+static DECOMPRESS_THREADS: AtomicUsize = AtomicUsize::new(4);
+static COUNT_THREADS: AtomicUsize = AtomicUsize::new(1);
+
+
+/// How many threads the corpus readers use for each stage
+#[derive(Clone, Copy, Debug)]
+pub struct ThreadConfig {
+    /// Threads which read archive entries or documents and extract tokens from them
+    pub extract: usize,
+    /// Threads which decompress whole entries ahead of the extract threads, where the reader
+    /// can separate decompression from extraction
+    pub decompress: usize,
+    /// Threads which merge the per entry counts
+    pub count: usize,
+}
+
+impl ThreadConfig {
+    /// Uses `threads` threads for extraction and decompression and a single counting thread
+    pub fn with_threads(threads: usize) -> ThreadConfig {
+        ThreadConfig {
+            extract: threads,
+            decompress: threads,
+            count: 1,
+        }
+    }
+}
+
+impl Default for ThreadConfig {
+    fn default() -> ThreadConfig {
+        ThreadConfig::with_threads(4)
+    }
+}
+
+/// Sets the thread counts used by the corpus readers. Readers look them up at the start of every
+/// pass over a corpus, so a change applies from the next pass, including for existing corpora.
+pub fn set_thread_config(config: ThreadConfig) {
+    EXTRACT_THREADS.store(config.extract.max(1), Ordering::Relaxed);
+    DECOMPRESS_THREADS.store(config.decompress.max(1), Ordering::Relaxed);
+    COUNT_THREADS.store(config.count.max(1), Ordering::Relaxed);
+}
+
+pub fn thread_config() -> ThreadConfig {
+    ThreadConfig {
+        extract: EXTRACT_THREADS.load(Ordering::Relaxed),
+        decompress: DECOMPRESS_THREADS.load(Ordering::Relaxed),
+        count: COUNT_THREADS.load(Ordering::Relaxed),
+    }
+}
+
+/// Puts all items into a queue which worker threads pull from until it is empty. Items are queued
+/// biggest first so that a few huge items don't end up being started last and leave one thread
+/// finishing them while the others are idle.
+pub fn largest_first_queue<'a, T: Sync, F: Fn(&T) -> usize>(items: &'a [T], size: F) -> Receiver<&'a T> {
+    let mut sorted = items.iter().collect_vec();
+    sorted.sort_by_key(|item| std::cmp::Reverse(size(item)));
+    let (snd, rcv) = unbounded();
+    for item in sorted {
+        snd.send(item).unwrap();
+    }
+    rcv
+}
+
+/// Reduces everything received from `rcv` using the configured number of counting threads. Each
+/// thread folds whatever it receives and then the partial results are combined.
+pub fn parallel_reduce<T: Send, F: Fn(T, T) -> T + Sync>(rcv: Receiver<T>, f: F) -> Option<T> {
+    let threads = thread_config().count;
+    if threads <= 1 {
+        return rcv.iter().reduce(&f);
+    }
+    crossbeam::scope(|scope| {
+        let handles = (0..threads).map(|_| {
+            let rcv_clone = rcv.clone();
+            let f_ref = &f;
+            scope.spawn(move |_| rcv_clone.iter().reduce(f_ref))
+        }).collect_vec();
+        handles.into_iter().filter_map(|handle| handle.join().unwrap()).reduce(&f)
+    }).unwrap()
 }
@@ -2,10 +2,12 @@ use std::fs::File;
 use std::path::Path;
 use std::sync::Arc;
 use std::iter::once;
-use std::str::from_utf8;
+use std::str::{from_utf8, FromStr};
 use itertools::Itertools;
+use simple_error::SimpleError;
+use crate::error::{Error, Result};
 
-use arrow2::array::{Array, Utf8Array, UInt32Array, Float64Array};
+use arrow2::array::{Array, BinaryArray, Utf8Array, UInt64Array, Float64Array};
 use arrow2::datatypes::{Field, Schema, DataType};
 use arrow2::io::parquet::write::{
     write_file, Compression, Encoding, Version, WriteOptions, RowGroupIterator
@@ -13,25 +15,90 @@ use arrow2::io::parquet::write::{
 use arrow2::record_batch::RecordBatch;
 
 
-fn get_schema(cols: &[&str]) -> Schema {
-    let word = Field::new("word", DataType::Utf8, false);
-    let count = Field::new("count", DataType::UInt32, false);
+/// What to do with words which aren't valid UTF-8, e.g. from Latin-1 subtitles
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NonUtf8Words {
+    /// Replace invalid bytes with U+FFFD. Words which end up the same are still separate rows.
+    Replace,
+    /// Leave the words out
+    Drop,
+    /// Write the word column as binary rather than UTF-8 so that all words are kept as they are
+    Binary,
+}
+
+impl FromStr for NonUtf8Words {
+    type Err = SimpleError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s == "replace" {
+            Ok(NonUtf8Words::Replace)
+        } else if s == "drop" {
+            Ok(NonUtf8Words::Drop)
+        } else if s == "binary" {
+            Ok(NonUtf8Words::Binary)
+        } else {
+            Err(SimpleError::new("Must be replace, drop or binary"))
+        }
+    }
+}
+
+/// How many word types and tokens weren't valid UTF-8
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct NonUtf8Summary {
+    pub types: u64,
+    pub tokens: u64,
+}
+
+fn get_schema(word_type: DataType, cols: &[&str]) -> Schema {
+    let word = Field::new("word", word_type, false);
+    let count = Field::new("count", DataType::UInt64, false);
     let mut fields = vec![word, count];
 
     fields.extend(cols.iter().map(|col| Field::new(col, DataType::Float64, false)));
     Schema::new(fields)
 }
 
-pub fn write_parquet(out_path: &Path, words: &[Box<[u8]>], counts: &[u32], col_names: &[&str], cols: &[&[f64]]) {
+/// Keeps the values of `col` for the rows which are true in `keep`
+fn filter_rows<T: Copy>(col: &[T], keep: &[bool]) -> Vec<T> {
+    col.iter().zip(keep.iter()).filter(|(_, keep)| **keep).map(|(val, _)| *val).collect_vec()
+}
+
+/// Words which aren't valid UTF-8 are dealt with according to `non_utf8` and counted in the
+/// returned summary
+pub fn write_parquet(
+    out_path: &Path,
+    words: &[Box<[u8]>],
+    counts: &[u64],
+    col_names: &[&str],
+    cols: &[&[f64]],
+    non_utf8: NonUtf8Words
+) -> Result<NonUtf8Summary> {
+    let mut summary = NonUtf8Summary::default();
+    let keep = words.iter().zip(counts.iter()).map(|(word, count)| {
+        let valid = from_utf8(word).is_ok();
+        if !valid {
+            summary.types += 1;
+            summary.tokens += count;
+        }
+        valid || non_utf8 != NonUtf8Words::Drop
+    }).collect_vec();
+    let (word_type, word_array): (DataType, Arc<dyn Array>) = match non_utf8 {
+        NonUtf8Words::Binary => (DataType::Binary, Arc::new(BinaryArray::<i32>::from_iter_values(words.iter()))),
+        NonUtf8Words::Replace | NonUtf8Words::Drop => (DataType::Utf8, Arc::new(Utf8Array::<i32>::from_iter_values(
+            words.iter().zip(keep.iter()).filter(|(_, keep)| **keep).map(|(word, _)| String::from_utf8_lossy(word))
+        ))),
+    };
     let mut col_arrays: Vec<Arc<dyn Array>> = vec![
-        Arc::new(Utf8Array::<i32>::from_iter_values(words.into_iter().map(|b| from_utf8(b).unwrap()))),
-        Arc::new(UInt32Array::from_slice(counts)),
+        word_array,
+        Arc::new(UInt64Array::from_slice(&filter_rows(counts, &keep))),
     ];
-    col_arrays.extend(cols.into_iter().map(|col| Arc::new(Float64Array::from_slice(col)) as Arc<dyn Array>));
+    col_arrays.extend(cols.into_iter().map(|col| {
+        Arc::new(Float64Array::from_slice(&filter_rows(col, &keep))) as Arc<dyn Array>
+    }));
     for col_array in col_arrays.iter() {
         println!("col_array len {}", (*col_array).len())
     }
-    let batch = RecordBatch::try_new(Arc::new(get_schema(col_names)), col_arrays).unwrap();
+    let batch = RecordBatch::try_new(Arc::new(get_schema(word_type, col_names)), col_arrays)?;
 
     let options = WriteOptions {
         write_statistics: true,
@@ -41,19 +108,20 @@ pub fn write_parquet(out_path: &Path, words: &[Box<[u8]>], counts: &[u32], col_n
 
     let schema = batch.schema().clone();
     let encodings = schema.fields().iter().map(|_| Encoding::Plain).collect_vec();
-    let row_groups = RowGroupIterator::try_new(once(Ok(batch)), &schema, options, encodings).unwrap();
+    let row_groups = RowGroupIterator::try_new(once(Ok(batch)), &schema, options, encodings)?;
     let parquet_schema = row_groups.parquet_schema().clone();
 
     // Create a new empty file
-    let mut file = File::create(out_path).unwrap();
+    let mut file = File::create(out_path).map_err(|err| Error::in_file(out_path, err.into()))?;
 
     // Write the file. Note that, at present, any error results in a corrupted file.
-    let _ = write_file(
+    write_file(
         &mut file,
         row_groups,
         &schema,
         parquet_schema,
         options,
         None,
-    ).unwrap();
+    )?;
+    Ok(summary)
 }
@@ -2,8 +2,15 @@ use std::io::prelude::*;
 use std::io::BufWriter;
 use std::path::Path;
 use std::fs::{create_dir_all, File};
+use crate::types::DocMeta;
+use crate::error::Result;
+use crate::json::write_json_string;
 
 
+/// Writes a term-document matrix in CSR form as little endian arrays: data_counts (u64),
+/// data_norm (f32 counts divided by the L2 norm of their document), indices (u32 word ids),
+/// indptr (u64 offsets of the entries of each document) and dims (u64 number of documents and
+/// vocabulary size)
 pub struct TermDocMatWriter {
     vocab_len: u64,
     num_docs: u64,
@@ -16,15 +23,15 @@ pub struct TermDocMatWriter {
 }
 
 impl TermDocMatWriter {
-    pub fn new(out_dir: &Path, vocab_len: u64) -> TermDocMatWriter {
-        create_dir_all(&out_dir).unwrap();
-        let data_counts = BufWriter::new(File::create(out_dir.join("data_counts")).unwrap());
-        let data_norm = BufWriter::new(File::create(out_dir.join("data_norm")).unwrap());
-        let indices = BufWriter::new(File::create(out_dir.join("indices")).unwrap());
-        let indptr = BufWriter::new(File::create(out_dir.join("indptr")).unwrap());
-        let dims = File::create(out_dir.join("indptr")).unwrap();
+    pub fn new(out_dir: &Path, vocab_len: u64) -> Result<TermDocMatWriter> {
+        create_dir_all(&out_dir)?;
+        let data_counts = BufWriter::new(File::create(out_dir.join("data_counts"))?);
+        let data_norm = BufWriter::new(File::create(out_dir.join("data_norm"))?);
+        let indices = BufWriter::new(File::create(out_dir.join("indices"))?);
+        let indptr = BufWriter::new(File::create(out_dir.join("indptr"))?);
+        let dims = File::create(out_dir.join("dims"))?;
 
-        TermDocMatWriter {
+        Ok(TermDocMatWriter {
             vocab_len,
             num_docs: 0,
             num_values: 0,
@@ -33,34 +40,68 @@ impl TermDocMatWriter {
             indices,
             indptr,
             dims,
-        }
+        })
     }
 
-    pub fn write_indexed_doc<'a, I: 'a>(&mut self, doc_words: u64, counts: &'a I) where &'a I: IntoIterator<Item=(&'a u32, &'a u32)> {
-        self.indptr.write(&self.num_values.to_le_bytes()).unwrap();
+    pub fn write_indexed_doc<'a, I: 'a>(&mut self, counts: &'a I) -> Result<()> where &'a I: IntoIterator<Item=(&'a u32, &'a u64)> {
+        self.indptr.write_all(&self.num_values.to_le_bytes())?;
         let mut total: f64 = 0.0;
         for (col, val) in counts {
-            self.indices.write(&col.to_le_bytes()).unwrap();
-            self.data_counts.write(&val.to_le_bytes()).unwrap();
-            total += (val * val) as f64;
+            self.indices.write_all(&col.to_le_bytes())?;
+            self.data_counts.write_all(&val.to_le_bytes())?;
+            total += (*val as f64).powi(2);
+            self.num_values += 1;
         }
         let total_sqrt = total.sqrt();
         for (_, val) in counts {
-            self.data_norm.write(&(((*val as f64) / total_sqrt) as f32).to_le_bytes()).unwrap();
+            self.data_norm.write_all(&(((*val as f64) / total_sqrt) as f32).to_le_bytes())?;
         }
-        self.num_values += doc_words;
         self.num_docs += 1;
+        Ok(())
+    }
+
+    pub fn close(mut self) -> Result<(u64, u64, u64)> {
+        self.indptr.write_all(&self.num_values.to_le_bytes())?;
+        self.data_counts.flush()?;
+        self.data_norm.flush()?;
+        self.indices.flush()?;
+        self.indptr.flush()?;
+        self.dims.write_all(&(self.num_docs as u64).to_le_bytes())?;
+        self.dims.write_all(&(self.vocab_len as u64).to_le_bytes())?;
+        self.dims.flush()?;
+        Ok((self.num_docs, self.vocab_len, self.num_values))
+    }
+}
+
+/// Writes the metadata of each document as a JSON object per line in the same order as the rows
+/// of the term-document matrix
+pub struct DocMetaWriter {
+    doc_meta: BufWriter<File>,
+}
+
+impl DocMetaWriter {
+    pub fn new(out_dir: &Path) -> Result<DocMetaWriter> {
+        create_dir_all(&out_dir)?;
+        let doc_meta = BufWriter::new(File::create(out_dir.join("doc_meta.jsonl"))?);
+        Ok(DocMetaWriter { doc_meta })
+    }
+
+    pub fn write_doc_meta(&mut self, meta: &DocMeta) -> Result<()> {
+        self.doc_meta.write_all(b"{")?;
+        for (idx, (key, value)) in meta.iter().enumerate() {
+            if idx > 0 {
+                self.doc_meta.write_all(b", ")?;
+            }
+            write_json_string(&mut self.doc_meta, key)?;
+            self.doc_meta.write_all(b": ")?;
+            write_json_string(&mut self.doc_meta, value)?;
+        }
+        self.doc_meta.write_all(b"}\n")?;
+        Ok(())
     }
 
-    pub fn close(mut self) -> (u64, u64, u64) {
-        self.indptr.write(&self.num_values.to_le_bytes()).unwrap();
-        self.data_counts.flush().unwrap();
-        self.data_norm.flush().unwrap();
-        self.indices.flush().unwrap();
-        self.indptr.flush().unwrap();
-        self.dims.write(&(self.num_docs as u64).to_le_bytes()).unwrap();
-        self.dims.write(&(self.vocab_len as u64).to_le_bytes()).unwrap();
-        self.dims.flush().unwrap();
-        (self.num_docs, self.vocab_len, self.num_values)
+    pub fn close(mut self) -> Result<()> {
+        self.doc_meta.flush()?;
+        Ok(())
     }
 }
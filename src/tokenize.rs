@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+
+use unicode_segmentation::UnicodeSegmentation;
+use crossbeam_channel::{unbounded, bounded, Receiver};
+use crossbeam::thread::Scope;
+use crate::types::{Corpus, DocBow};
+use crate::vocab::{VocabBuilder, VocabMap};
+use crate::parallel::parallel_reduce;
+use crate::error::{ErrorLog, Result};
+
+
+pub type DocIter<'a> = dyn Iterator<Item=Vec<u8>> + 'a;
+
+
+/// Splits raw text into words according to the UAX #29 word boundary rules, skipping
+/// punctuation and whitespace.
+pub fn for_each_word<F: FnMut(&[u8])>(text: &[u8], mut f: F) {
+    let text = String::from_utf8_lossy(text);
+    for word in text.unicode_words() {
+        f(word.as_bytes());
+    }
+}
+
+/// A source of untokenised documents. Implementations spawn their own reader threads within
+/// `scope` and call `cb` once per worker thread with an iterator over that thread's documents.
+pub trait RawTextSource: Sync {
+    fn for_each_doc_batch<'env, F>(&'env self, scope: &Scope<'env>, cb: F) -> ()
+        where F: Fn(&mut DocIter) -> () + Send + Clone + 'env;
+
+    /// Where documents which can't be read are reported
+    fn errors(&self) -> &ErrorLog;
+}
+
+/// Turns a RawTextSource into a Corpus by tokenising each document with `for_each_word`.
+pub struct TokenizedCorpus<S: RawTextSource> {
+    source: S
+}
+
+impl<S: RawTextSource> TokenizedCorpus<S> {
+    pub fn new(source: S) -> TokenizedCorpus<S> {
+        TokenizedCorpus { source }
+    }
+}
+
+impl<S: RawTextSource> Corpus for TokenizedCorpus<S> {
+    fn count_words(&self) -> Result<(VocabBuilder, u64)> {
+        let counts = crossbeam::scope(|scope| {
+            let (snd, rcv) = unbounded();
+            self.source.for_each_doc_batch(scope, move |docs| {
+                let mut vocab = VocabBuilder::new();
+                let mut doc_count = 0;
+                for doc in docs {
+                    for_each_word(&doc, |tok| vocab.add(tok));
+                    doc_count += 1;
+                }
+                snd.send((vocab, doc_count)).unwrap()
+            });
+            parallel_reduce(rcv, |(mut acc, acc_docs), (other, other_docs)| {
+                acc.merge(other);
+                (acc, acc_docs + other_docs)
+            }).unwrap_or_else(|| (VocabBuilder::new(), 0))
+        }).unwrap();
+        self.source.errors().check()?;
+        Ok(counts)
+    }
+
+    fn gen_doc_bows<'env>(&'env self, scope: &Scope<'env>, vocab: &'env VocabMap) -> Receiver<Result<DocBow>> {
+        let (snd, rcv) = bounded(1024);
+        self.source.for_each_doc_batch(scope, move |docs| {
+            for doc in docs {
+                let mut counts: BTreeMap<u32, u64> = BTreeMap::new();
+                let mut doc_words = 0;
+                for_each_word(&doc, |tok| {
+                    let maybe_vocab_idx = vocab.get(tok);
+                    if let Some(vocab_idx) = maybe_vocab_idx {
+                        *counts.entry(*vocab_idx).or_insert(0) += 1;
+                        doc_words += 1;
+                    }
+                });
+                if snd.send((doc_words, counts)).is_err() {
+                    return;
+                }
+            }
+        });
+        self.source.errors().forward_checked(scope, rcv)
+    }
+}
@@ -1,12 +1,39 @@
 use std::collections::BTreeMap;
-use crossbeam_channel::Receiver;
+use crossbeam_channel::{bounded, Receiver};
+use crate::error::Result;
 use crate::vocab::{VocabBuilder, VocabMap};
 use crossbeam::thread::Scope;
 
 
-pub type DocBow = (u32, BTreeMap<u32, u32>);
+/// Which form of each token to count
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TokenKey {
+    WordForm,
+    Lemma,
+}
+
+pub type DocBow = (u64, BTreeMap<u32, u64>);
+/// Metadata of a document such as its date, genre or source file, by attribute name
+pub type DocMeta = BTreeMap<String, String>;
+
+/// A corpus which can be read any number of times. Errors which stop reading are sent as the last
+/// item of the document receivers.
+pub trait Corpus: Sync {
+    fn count_words(&self) -> Result<(VocabBuilder, u64)>;
+    fn gen_doc_bows<'env>(&'env self, scope: &Scope<'env>, vocab: &'env VocabMap) -> Receiver<Result<DocBow>>;
 
-pub trait Corpus {
-    fn count_words(&self) -> (VocabBuilder, u32);
-    fn gen_doc_bows<'env>(&'env self, scope: &Scope<'env>, vocab: &'env VocabMap) -> Receiver<DocBow>;
+    /// Like gen_doc_bows but with the metadata of each document. Corpora which don't have any
+    /// document metadata give empty metadata.
+    fn gen_doc_bows_with_meta<'env>(&'env self, scope: &Scope<'env>, vocab: &'env VocabMap) -> Receiver<Result<(DocBow, DocMeta)>> {
+        let doc_rcv = self.gen_doc_bows(scope, vocab);
+        let (snd, rcv) = bounded(1024);
+        scope.spawn(move |_| {
+            for doc in doc_rcv {
+                if snd.send(doc.map(|doc| (doc, DocMeta::new()))).is_err() {
+                    return;
+                }
+            }
+        });
+        rcv
+    }
 }
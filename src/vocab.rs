@@ -1,11 +1,13 @@
 use std::collections::BTreeMap;
 use itertools::Itertools;
 use std::convert::TryInto;
-use std::io::BufRead;
+use std::io::{self, BufRead};
 use std::str;
 use std::io::BufReader;
 use fnv::FnvHashMap;
 use std::fs::{create_dir_all, File};
+use std::path::Path;
+use crate::error::{Error, Result};
 
 
 pub type VocabMap = FnvHashMap<Box<[u8]>, u32>;
@@ -13,29 +15,28 @@ pub type VocabMap = FnvHashMap<Box<[u8]>, u32>;
 
 // XXX: Reduce allocations using SmartString/SmallVec
 pub struct VocabBuilder {
-    pub acc: BTreeMap::<Box<[u8]>, u32>
+    pub acc: BTreeMap::<Box<[u8]>, u64>
 }
 
 impl VocabBuilder {
     pub fn new() -> VocabBuilder {
         VocabBuilder {
-            acc: BTreeMap::<Box<[u8]>, u32>::new()
+            acc: BTreeMap::<Box<[u8]>, u64>::new()
         }
     }
 
-    fn inc_key_ref(&mut self, key: &[u8], inc: u32) {
-        /*
-        XXX: Switch to raw_entry API when supported
-
-        Could use something like...
-        if !self.acc.contains_key(key) {
-            self.acc.insert(key.into(), inc);
+    fn inc_key_ref(&mut self, key: &[u8], inc: u64) {
+        // XXX: Switch to raw_entry API when supported to avoid looking up new keys twice
+        // Most tokens are already in the vocab, so look them up by reference and only copy new ones
+        match self.acc.get_mut(key) {
+            Some(cnt) => *cnt += inc,
+            None => {
+                self.acc.insert(key.into(), inc);
+            }
         }
-        */
-        self.inc_key_owned(key.into(), inc);
     }
 
-    fn inc_key_owned(&mut self, key: Box<[u8]>, inc: u32) {
+    fn inc_key_owned(&mut self, key: Box<[u8]>, inc: u64) {
         *self.acc.entry(key).or_insert(0) += inc;
     }
 
@@ -49,7 +50,7 @@ impl VocabBuilder {
         }
     }
 
-    pub fn build(self) -> (VocabMap, Vec<u32>, u32) {
+    pub fn build(self) -> (VocabMap, Vec<u64>, u64) {
         let mut word_freqs_strings = self.acc
             .into_iter()
             .collect_vec();
@@ -62,7 +63,7 @@ impl VocabBuilder {
                 });
         let mut vocab: VocabMap = VocabMap::default();
         let mut word_freqs_indexed = Vec::with_capacity(word_freqs_strings.len());
-        let mut total_words: u32 = 0;
+        let mut total_words: u64 = 0;
         for (idx, (word, cnt)) in word_freqs_strings.into_iter().enumerate() {
             vocab.insert(word, (idx as u32).try_into().unwrap());
             word_freqs_indexed.push(cnt);
@@ -73,29 +74,30 @@ impl VocabBuilder {
 }
 
 
-pub fn get_numberbatch_vocab(in_path: &str) -> VocabMap {
+pub fn get_numberbatch_vocab(in_path: &str) -> Result<VocabMap> {
     // XXX: Inefficient: reads a bunch of stuff just to throw it away and then copies the vocab
-    let file = File::open(in_path).unwrap();
+    let in_file = |err: io::Error| Error::in_file(Path::new(in_path), err.into());
+    let file = File::open(in_path).map_err(in_file)?;
     let mut reader = BufReader::new(file);
     let mut buf = Vec::<u8>::with_capacity(64);
     let mut vocab = FnvHashMap::<Box<[u8]>, u32>::default();
     let mut idx = 0;
     loop {
-        let read_bytes = reader.read_until(b' ', &mut buf).unwrap();
+        let read_bytes = reader.read_until(b' ', &mut buf).map_err(in_file)?;
         if read_bytes == 0 {
             break;
         }
         vocab.insert(buf.as_slice()[..buf.len()-1].to_owned().into_boxed_slice(), idx);
         buf.clear();
         // XXX: Strictly we would prefer to have a discard_until
-        let read_bytes = reader.read_until(b'\n', &mut buf).unwrap();
+        let read_bytes = reader.read_until(b'\n', &mut buf).map_err(in_file)?;
         if read_bytes == 0 {
             break;
         }
         buf.clear();
         idx += 1;
     }
-    return vocab;
+    Ok(vocab)
 }
 /*
 fn merge_many(vocabs: &mut [Vocab]) -> Vocab {
@@ -1,22 +1,200 @@
-use std::io::BufReader;
-use std::io;
+use std::io::{self, BufRead};
+use std::borrow::Cow;
 use std::path::Path;
+use std::str::FromStr;
 use std::collections::BTreeMap;
 
 use memmap::Mmap;
-use quick_xml::events::Event;
+use quick_xml::events::BytesStart;
+use quick_xml::escape::unescape;
 use piz::read::FileMetadata;
 use std::ffi::OsStr;
 use crate::vocab::{VocabBuilder, VocabMap};
-use crate::types::{Corpus, DocBow};
-use crate::zip::{MinEntries, open_piz, EntryBufReader};
+use crate::types::{Corpus, DocBow, DocMeta, TokenKey};
+use crate::zip::{MinEntry, MinEntries, open_piz, EntryBufReader, compressed_size, entry_path};
 use crossbeam_channel::{unbounded, bounded, Receiver};
 use crossbeam::thread::Scope;
-use crate::zip::{read_buf, UNZIP_READERS};
-use crate::parallel::partition;
-use crate::conllu::grab_lemma;
+use crate::zip::read_buf;
+use crate::parallel::{largest_first_queue, parallel_reduce, thread_config};
+use crate::conllu::trim_ascii;
+use crate::error::{Error, ErrorLog};
+use simple_error::SimpleError;
 
 
+// Korp VRT files without a positional attributes declaration are assumed to start with the
+// positional attributes word, ref, lemma, ...
+pub const WORD_COLUMN: usize = 0;
+pub const LEMMA_COLUMN: usize = 2;
+
+
+pub fn token_key_column(token_key: TokenKey) -> usize {
+    match token_key {
+        TokenKey::WordForm => WORD_COLUMN,
+        TokenKey::Lemma => LEMMA_COLUMN,
+    }
+}
+
+/// Parses a `<!-- #vrt positional-attributes: word ref lemma ... -->` comment, given the text
+/// between the comment delimiters
+pub fn parse_positional_attributes(comment: &[u8]) -> Option<Vec<String>> {
+    let comment = String::from_utf8_lossy(comment);
+    let attrs = comment.trim().strip_prefix("#vrt positional-attributes:")?;
+    Some(attrs.split_ascii_whitespace().map(str::to_owned).collect())
+}
+
+/// The positional attribute to take tokens from
+#[derive(Clone, Debug)]
+pub struct AttrSelection {
+    /// The names the attribute might have. The first one a file declares is used.
+    names: Vec<String>,
+    /// The column to use for files which don't declare their positional attributes
+    undeclared_column: Option<usize>,
+}
+
+impl FromStr for AttrSelection {
+    type Err = SimpleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.contains(char::is_whitespace) {
+            return Err(SimpleError::new("Must be the name of a positional attribute, e.g. lemma or pos"));
+        }
+        Ok(AttrSelection { names: vec![s.to_owned()], undeclared_column: None })
+    }
+}
+
+impl AttrSelection {
+    /// Word forms are `word` in all the Korp corpora. Lemmas are usually `lemma`, but some older
+    /// corpora only have `baseform`.
+    pub fn from_token_key(token_key: TokenKey) -> AttrSelection {
+        let names: &[&str] = match token_key {
+            TokenKey::WordForm => &["word"],
+            TokenKey::Lemma => &["lemma", "baseform"],
+        };
+        AttrSelection {
+            names: names.iter().map(|name| (*name).to_owned()).collect(),
+            undeclared_column: Some(token_key_column(token_key)),
+        }
+    }
+
+    /// Finds the column of the attribute among the declared `attrs`
+    pub fn resolve(&self, attrs: &[String]) -> Result<usize, Error> {
+        for name in self.names.iter() {
+            if let Some(column) = attrs.iter().position(|attr| attr == name) {
+                return Ok(column);
+            }
+        }
+        Err(Error::Config(format!(
+            "No positional attribute {} in VRT file with positional attributes {}",
+            self.names.join(" or "),
+            attrs.join(" ")
+        )))
+    }
+
+    /// The column to use before any positional attributes have been declared. This is an error
+    /// if an attribute was asked for by name since we can't know where it is.
+    pub fn resolve_undeclared(&self) -> Result<usize, Error> {
+        match self.undeclared_column {
+            Some(column) => Ok(column),
+            None => Err(Error::Config(format!(
+                "VRT file has no #vrt positional-attributes declaration to find {} in",
+                self.names.join(" or ")
+            ))),
+        }
+    }
+}
+
+/// The structural element which makes up a document in a VRT file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VrtDocUnit {
+    Text,
+    Paragraph,
+    Sentence,
+    /// Any other element by name
+    Element(String),
+}
+
+impl FromStr for VrtDocUnit {
+    type Err = SimpleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "text" {
+            Ok(VrtDocUnit::Text)
+        } else if s == "paragraph" {
+            Ok(VrtDocUnit::Paragraph)
+        } else if s == "sentence" {
+            Ok(VrtDocUnit::Sentence)
+        } else if let Some(name) = s.strip_prefix("element:") {
+            Ok(VrtDocUnit::Element(name.to_owned()))
+        } else {
+            Err(SimpleError::new("Must be text, paragraph, sentence or element:<name>"))
+        }
+    }
+}
+
+impl Default for VrtDocUnit {
+    fn default() -> VrtDocUnit {
+        VrtDocUnit::Text
+    }
+}
+
+// Different corpora use different names for the same structures
+const PARAGRAPH_ELEMS: [&str; 2] = ["paragraph", "p"];
+const SENTENCE_ELEMS: [&str; 2] = ["sentence", "s"];
+
+fn elem_names(names: &[&str]) -> Vec<Box<[u8]>> {
+    names.iter().map(|name| Box::from(name.as_bytes())).collect()
+}
+
+/// The names of the structural elements to read from VRT files
+#[derive(Clone, Debug)]
+pub struct VrtElems {
+    /// Elements which make up documents
+    docs: Vec<Box<[u8]>>,
+    /// Elements whose token lines are counted
+    containers: Vec<Box<[u8]>>,
+    /// Whether to count the token lines of documents which contain no container elements at all
+    uncontained_fallback: bool,
+}
+
+impl VrtElems {
+    /// With no `token_elem`, tokens are taken from any sentence or paragraph elements, or from
+    /// anywhere in documents which have neither
+    pub fn new(doc_unit: &VrtDocUnit, token_elem: Option<&str>) -> VrtElems {
+        let docs = match doc_unit {
+            VrtDocUnit::Text => elem_names(&["text"]),
+            VrtDocUnit::Paragraph => elem_names(&PARAGRAPH_ELEMS),
+            VrtDocUnit::Sentence => elem_names(&SENTENCE_ELEMS),
+            VrtDocUnit::Element(name) => elem_names(&[name]),
+        };
+        let (containers, uncontained_fallback) = match token_elem {
+            Some(name) => (elem_names(&[name]), false),
+            None => (elem_names(&[&SENTENCE_ELEMS[..], &PARAGRAPH_ELEMS[..]].concat()), true),
+        };
+        VrtElems { docs, containers, uncontained_fallback }
+    }
+
+    fn is_doc(&self, name: &[u8]) -> bool {
+        self.docs.iter().any(|doc| doc.as_ref() == name)
+    }
+
+    fn is_container(&self, name: &[u8]) -> bool {
+        self.containers.iter().any(|container| container.as_ref() == name)
+    }
+}
+
+/// Options for reading VRT files
+#[derive(Clone, Debug, Default)]
+pub struct VrtOptions {
+    /// The positional attribute to take tokens from. Defaults to word or lemma depending on the
+    /// token key.
+    pub attr: Option<AttrSelection>,
+    pub doc_unit: VrtDocUnit,
+    /// The element to take token lines from. Detected automatically by default.
+    pub token_elem: Option<String>,
+    /// Log and skip malformed lines rather than stopping at the first one
+    pub lenient: bool,
+}
+
 fn is_vrt_file(entry: &FileMetadata) -> bool {
     if !entry.is_file() {
         return false;
@@ -29,128 +207,426 @@ fn is_vrt_file(entry: &FileMetadata) -> bool {
 }
 
 
-struct VrtFile<'a, 'b, F> {
-    buf: Vec::<u8>,
-    reader: &'b mut quick_xml::Reader<BufReader<Box<dyn io::Read + Send + 'a>>>,
+/// Parses the attributes of a structural element and adds them to `meta` if given, named like
+/// Korp's structural attributes, e.g. text_date. They are parsed either way so that bad
+/// attributes are found whether or not metadata is being collected.
+fn add_elem_meta(elem: &BytesStart, mut meta: Option<&mut DocMeta>) -> Result<(), quick_xml::Error> {
+    let elem_name = String::from_utf8_lossy(elem.name()).into_owned();
+    for attr in elem.attributes() {
+        let attr = attr?;
+        let value = attr.unescaped_value()?;
+        if let Some(meta) = meta.as_mut() {
+            meta.insert(
+                format!("{}_{}", elem_name, String::from_utf8_lossy(attr.key)),
+                String::from_utf8_lossy(&value).into_owned()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// What a line of a VRT file holds
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum LineKind {
+    Blank,
+    Token,
+    /// A start tag, with the length of the element name
+    Start(usize),
+    End,
+    Comment,
+    /// Empty elements, declarations and processing instructions, which don't affect anything
+    Ignored,
+}
+
+/// Works out what a line holds. Structural lines are always a single tag on their own line.
+fn classify_line(line: &[u8]) -> Result<LineKind, &'static str> {
+    let trimmed = trim_ascii(line);
+    if trimmed.is_empty() {
+        return Ok(LineKind::Blank);
+    }
+    if trimmed[0] != b'<' {
+        return Ok(LineKind::Token);
+    }
+    if trimmed.starts_with(b"<!--") {
+        return if trimmed.ends_with(b"-->") { Ok(LineKind::Comment) } else { Err("unterminated comment") };
+    }
+    if !trimmed.ends_with(b">") {
+        return Err("unterminated tag");
+    }
+    if trimmed.starts_with(b"</") {
+        return Ok(LineKind::End);
+    }
+    if trimmed.starts_with(b"<!") || trimmed.starts_with(b"<?") || trimmed.ends_with(b"/>") {
+        return Ok(LineKind::Ignored);
+    }
+    let name_len = trimmed[1..trimmed.len() - 1].iter()
+        .position(|chr| chr.is_ascii_whitespace())
+        .unwrap_or(trimmed.len() - 2);
+    if name_len == 0 {
+        return Err("tag without a name");
+    }
+    Ok(LineKind::Start(name_len))
+}
+
+/// A line of a VRT file as returned by `VrtReader`
+enum VrtLine<'a> {
+    Token(&'a [u8]),
+    Start(BytesStart<'a>),
+    /// The closed elements have already been popped from `VrtReader::open`
+    End,
+    /// The text between the comment delimiters
+    Comment(&'a [u8]),
+    Eof,
+}
+
+/// Reads VRT files line by line, keeping track of the open structural elements. This doesn't
+/// need the file to be well-formed XML. In lenient mode bad lines are logged and skipped and
+/// unclosed elements are closed by the next end tag which matches an element further out.
+/// Otherwise they are reported to the error log and the rest of the file is skipped.
+pub struct VrtReader<'a> {
+    reader: EntryBufReader<'a>,
+    path: &'a Path,
+    lenient: bool,
+    errors: &'a ErrorLog,
+    /// Set once an error has been reported, after which the file appears to end
+    failed: bool,
+    line: Vec<u8>,
+    line_no: u64,
+    /// The names of the currently open elements, outermost first
+    open: Vec<Box<[u8]>>,
+}
+
+impl<'a> VrtReader<'a> {
+    fn new(reader: EntryBufReader<'a>, path: &'a Path, lenient: bool, errors: &'a ErrorLog) -> VrtReader<'a> {
+        VrtReader { reader, path, lenient, errors, failed: false, line: Vec::new(), line_no: 0, open: Vec::new() }
+    }
+
+    /// Reports that the rest of the file can't be read
+    fn fail(&mut self, error: Error) {
+        self.errors.report(Some(self.path), error);
+        self.failed = true;
+    }
+
+    /// Reports a problem with the current line, which is only a warning in lenient mode
+    fn malformed(&mut self, msg: &str) {
+        let line = String::from_utf8_lossy(&self.line).trim_end().to_owned();
+        if self.lenient {
+            eprintln!("Warning: malformed VRT at {}:{}: {}: {}", self.path.display(), self.line_no, msg, line);
+        } else {
+            self.fail(Error::Format(format!("malformed VRT at line {}: {}: {}", self.line_no, msg, line)));
+        }
+    }
+
+    fn next_line(&mut self) -> VrtLine<'_> {
+        loop {
+            if self.failed {
+                return VrtLine::Eof;
+            }
+            self.line.clear();
+            match self.reader.read_until(b'\n', &mut self.line) {
+                Ok(0) => return VrtLine::Eof,
+                Ok(_) => (),
+                Err(err) => {
+                    let line_no = self.line_no;
+                    self.fail(io::Error::new(err.kind(), format!("{} after line {}", err, line_no)).into());
+                    return VrtLine::Eof;
+                }
+            }
+            self.line_no += 1;
+            match classify_line(&self.line) {
+                Ok(LineKind::Blank) | Ok(LineKind::Ignored) => (),
+                Ok(LineKind::Token) => {
+                    let line = self.line.strip_suffix(b"\n").unwrap_or(&self.line);
+                    return VrtLine::Token(line.strip_suffix(b"\r").unwrap_or(line));
+                },
+                Ok(LineKind::Start(name_len)) => {
+                    let trimmed = trim_ascii(&self.line);
+                    let content = &trimmed[1..trimmed.len() - 1];
+                    self.open.push(Box::from(&content[..name_len]));
+                    return VrtLine::Start(BytesStart::borrowed(content, name_len));
+                },
+                Ok(LineKind::End) => {
+                    let trimmed = trim_ascii(&self.line);
+                    let name = trim_ascii(&trimmed[2..trimmed.len() - 1]);
+                    match self.open.iter().rposition(|elem| elem.as_ref() == name) {
+                        Some(idx) => {
+                            if idx + 1 < self.open.len() {
+                                let unclosed = self.open[idx + 1..].iter()
+                                    .map(|elem| format!("<{}>", String::from_utf8_lossy(elem)))
+                                    .collect::<Vec<_>>()
+                                    .join(" ");
+                                self.malformed(&format!("end tag closes unclosed {}", unclosed));
+                            }
+                            self.open.truncate(idx);
+                            return VrtLine::End;
+                        },
+                        None => self.malformed("end tag without a start tag"),
+                    }
+                },
+                Ok(LineKind::Comment) => {
+                    let trimmed = trim_ascii(&self.line);
+                    return VrtLine::Comment(&trimmed[4..trimmed.len() - 3]);
+                },
+                Err(reason) => self.malformed(reason),
+            }
+        }
+    }
+}
+
+struct VrtFile<'a, 'b, 'c, F> {
+    reader: &'b mut VrtReader<'a>,
+    attr: &'c AttrSelection,
+    elems: &'c VrtElems,
+    column: Option<usize>,
+    /// Metadata for every document in the file, or None to skip collecting metadata
+    file_meta: Option<DocMeta>,
+    /// The metadata of the current <text>, which documents within it inherit
+    text_meta: DocMeta,
+    /// Whether any document element has been seen
+    found_doc: bool,
+    /// Whether any token lines have been seen outside of documents
+    stray_tokens: bool,
     proc_doc: F
 }
 
-impl<'a, 'b, F> VrtFile<'a, 'b, F> {
+impl<'a, 'b, 'c, F> VrtFile<'a, 'b, 'c, F> {
     fn new(
-        reader: &'b mut quick_xml::Reader<BufReader<Box<dyn io::Read + Send + 'a>>>,
+        reader: &'b mut VrtReader<'a>,
+        attr: &'c AttrSelection,
+        elems: &'c VrtElems,
         proc_doc: F
-    ) -> VrtFile<'a, 'b, F> {
-        VrtFile::new_with_buf(Vec::new(), reader, proc_doc)
+    ) -> VrtFile<'a, 'b, 'c, F> {
+        VrtFile {
+            reader,
+            attr,
+            elems,
+            column: None,
+            file_meta: None,
+            text_meta: DocMeta::new(),
+            found_doc: false,
+            stray_tokens: false,
+            proc_doc
+        }
     }
 
-    fn new_with_buf(
-        buf: Vec::<u8>,
-        reader: &'b mut quick_xml::Reader<BufReader<Box<dyn io::Read + Send + 'a>>>,
-        proc_doc: F
-    ) -> VrtFile<'a, 'b, F> {
-        VrtFile { buf, reader, proc_doc }
+    /// Collect metadata for each document starting with `file_meta`
+    fn with_meta(mut self, file_meta: DocMeta) -> VrtFile<'a, 'b, 'c, F> {
+        self.file_meta = Some(file_meta);
+        self
     }
 }
 
-impl<'a, 'b, R, F: FnMut(VrtText) -> Option<R>> Iterator for VrtFile<'a, 'b, F> {
+impl<'a, 'b, 'c, R, F: FnMut(VrtText) -> Option<R>> Iterator for VrtFile<'a, 'b, 'c, F> {
     type Item = R;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            match self.reader.read_event(&mut self.buf) {
-                Ok(Event::Start(ref e)) => {
-                    match e.name() {
-                        b"text" => {
-                            return (self.proc_doc)(VrtText {
-                                reader: self.reader,
-                                buf: &mut self.buf
-                            });
+            let collect_meta = self.file_meta.is_some();
+            // The attributes of the document element itself, if it isn't <text>
+            let mut elem_meta = DocMeta::new();
+            let mut meta_result = Ok(());
+            let doc_elem = match self.reader.next_line() {
+                VrtLine::Start(ref e) => {
+                    let is_doc = self.elems.is_doc(e.name());
+                    if e.name() == b"text" {
+                        self.text_meta = self.file_meta.clone().unwrap_or_default();
+                        meta_result = add_elem_meta(e, if collect_meta { Some(&mut self.text_meta) } else { None });
+                    } else if is_doc {
+                        meta_result = add_elem_meta(e, if collect_meta { Some(&mut elem_meta) } else { None });
+                    }
+                    if !is_doc {
+                        None
+                    } else {
+                        Some(e.name().to_vec())
+                    }
+                },
+                VrtLine::Comment(comment) => {
+                    let attr = self.attr;
+                    match parse_positional_attributes(comment).map(|attrs| attr.resolve(&attrs)) {
+                        Some(Ok(column)) => self.column = Some(column),
+                        Some(Err(err)) => {
+                            self.reader.fail(err);
+                            return None;
                         },
-                        _ => {}
+                        None => (),
+                    }
+                    None
+                },
+                VrtLine::Eof => {
+                    if self.stray_tokens && !self.found_doc && !self.reader.failed {
+                        // Most likely --vrt-doc-unit doesn't match the file
+                        let docs = self.elems.docs.iter()
+                            .map(|elem| format!("<{}>", String::from_utf8_lossy(elem)))
+                            .collect::<Vec<_>>()
+                            .join(" or ");
+                        self.reader.fail(Error::Format(format!("token lines but no {} document elements", docs)));
                     }
+                    return None;
                 },
-                Ok(Event::Eof) => return None,
-                Err(e) => panic!("Error at position {}: {:?}", self.reader.buffer_position(), e),
-                _ => (),
+                VrtLine::Token(_) => {
+                    self.stray_tokens = true;
+                    None
+                },
+                VrtLine::End => None,
+            };
+            if let Err(err) = meta_result {
+                self.reader.malformed(&format!("bad attributes in start tag ({:?})", err));
+            }
+            if let Some(doc_elem) = doc_elem {
+                self.found_doc = true;
+                let column = match self.column {
+                    Some(column) => column,
+                    None => match self.attr.resolve_undeclared() {
+                        Ok(column) => *self.column.insert(column),
+                        Err(err) => {
+                            self.reader.fail(err);
+                            return None;
+                        }
+                    }
+                };
+                let mut meta = DocMeta::new();
+                if collect_meta {
+                    if !self.reader.open.iter().any(|elem| elem.as_ref() == b"text") {
+                        // The <text> which text_meta came from has been closed since
+                        self.text_meta = self.file_meta.clone().unwrap_or_default();
+                    }
+                    meta = self.text_meta.clone();
+                    meta.extend(elem_meta);
+                }
+                let doc_depth = self.reader.open.len();
+                return (self.proc_doc)(VrtText {
+                    reader: self.reader,
+                    elems: self.elems,
+                    doc_elem,
+                    doc_depth,
+                    column,
+                    meta,
+                });
             }
-            self.buf.clear();
         }
     }
 }
 
 struct VrtText<'a, 'b> {
-    reader: &'a mut quick_xml::Reader<BufReader<Box<dyn io::Read + Send + 'b>>>,
-    buf: &'a mut Vec::<u8>,
-
+    reader: &'a mut VrtReader<'b>,
+    elems: &'a VrtElems,
+    doc_elem: Vec<u8>,
+    /// How many elements are open inside the document element, including it
+    doc_depth: usize,
+    column: usize,
+    meta: DocMeta,
 }
 
 impl<'a, 'b> VrtText<'a, 'b> {
+    /// How many container elements are open within the document, including the document itself
+    fn container_depth(&self) -> usize {
+        self.reader.open[self.doc_depth - 1..].iter()
+            .filter(|elem| self.elems.is_container(elem))
+            .count()
+    }
+
     fn for_each<F>(self, mut f: F)
     where F: FnMut(&[u8])
     {
-        let mut in_sent = false;
+        // Documents which are themselves containers, e.g. sentences, count all their tokens
+        let mut container_depth = self.container_depth();
+        let mut seen_container = container_depth > 0;
+        // Tokens outside any container, which are used if the document turns out to have none
+        let mut uncontained: Vec<Vec<u8>> = Vec::new();
         loop {
-            match self.reader.read_event(self.buf) {
-                Ok(Event::Start(ref e)) => {
-                    match e.name() {
-                        b"sentence" => {
-                            in_sent = true;
-                        },
-                        _ => {}
-                    }
+            match self.reader.next_line() {
+                VrtLine::Start(_) => {
+                    container_depth = self.container_depth();
+                    seen_container = seen_container || container_depth > 0;
                 },
-                Ok(Event::End(ref e)) => {
-                    match e.name() {
-                        b"text" => {
-                            return;
-                        },
-                        b"sentence" => {
-                            in_sent = false
-                        },
-                        _ => {}
+                VrtLine::End => {
+                    if self.reader.open.len() < self.doc_depth {
+                        break;
                     }
+                    container_depth = self.container_depth();
                 },
-                Ok(Event::Text(ref e)) => {
-                    if in_sent {
-                        let unescaped = e.unescaped().unwrap();
-                        f(grab_lemma(unescaped.as_ref()));
+                VrtLine::Token(line) => {
+                    if container_depth == 0 && (!self.elems.uncontained_fallback || seen_container) {
+                        continue;
+                    }
+                    let tok = match line.split(|chr| *chr == b'\t').nth(self.column) {
+                        Some(tok) => tok,
+                        None => {
+                            self.reader.malformed("too few columns in token line");
+                            continue;
+                        }
+                    };
+                    let tok = if tok.contains(&b'&') {
+                        match unescape(tok) {
+                            Ok(tok) => tok,
+                            Err(err) => {
+                                self.reader.malformed(&format!("bad entity in token line ({:?})", err));
+                                continue;
+                            }
+                        }
+                    } else {
+                        Cow::Borrowed(tok)
+                    };
+                    if container_depth > 0 {
+                        f(&tok);
+                    } else {
+                        uncontained.push(tok.into_owned());
                     }
                 },
-                Ok(Event::Eof) => {
-                    // Filename might be useful
+                VrtLine::Comment(_) => (),
+                VrtLine::Eof => {
+                    if self.reader.failed {
+                        break;
+                    }
                     eprintln!(
-                        "Premature end of VRT file at {}! Ended inside <text>.",
-                        self.reader.buffer_position()
+                        "Premature end of VRT file {}! Ended inside <{}>.",
+                        self.reader.path.display(),
+                        String::from_utf8_lossy(&self.doc_elem)
                     );
-                    return;
+                    break;
                 },
-                Err(e) => panic!("Error at position {}: {:?}", self.reader.buffer_position(), e),
-                _ => (),
             }
-            self.buf.clear();
+        }
+        if !seen_container {
+            for tok in uncontained {
+                f(&tok);
+            }
         }
     }
 }
 
+/// Calls `cb` on a VrtReader for each entry using the extraction threads, each of which stops
+/// once `cb` returns false
 pub fn buffered_extract<'env, F>(
     scope: &Scope<'env>,
     vrt_entries: &'env MinEntries,
     mmap: &'env Mmap,
+    lenient: bool,
+    errors: &'env ErrorLog,
     cb: F
 ) -> ()
-    where F: Fn(quick_xml::Reader<EntryBufReader>) -> () + Send + Clone + 'env
+    where F: Fn(&'env MinEntry, &mut VrtReader) -> bool + Send + Clone + 'env
 {
-    let entries_partitioned = partition(
-        &vrt_entries,
-        UNZIP_READERS
-    );
-    println!("Extracting zip entries using {} threads", UNZIP_READERS);
-    for entry_slice in entries_partitioned {
+    // VRT entries can be huge so they are streamed and decompressed by the extraction threads
+    let threads = thread_config().extract;
+    let entry_queue = largest_first_queue(vrt_entries, compressed_size);
+    println!("Extracting zip entries using {} threads", threads);
+    for _ in 0..threads {
+        let entry_queue_clone = entry_queue.clone();
         let cb_clone = cb.clone();
         scope.spawn(move |_| {
-            for entry in entry_slice {
-                let contents = read_buf(mmap, entry);
-                cb_clone(quick_xml::Reader::from_reader(contents));
+            for entry in entry_queue_clone {
+                if errors.failed() {
+                    break;
+                }
+                match read_buf(mmap, entry) {
+                    Ok(contents) => {
+                        if !cb_clone(entry, &mut VrtReader::new(contents, entry_path(entry), lenient, errors)) {
+                            break;
+                        }
+                    },
+                    Err(err) => errors.report(Some(entry_path(entry)), err.into()),
+                }
             }
         });
     }
@@ -158,40 +634,44 @@ pub fn buffered_extract<'env, F>(
 
 pub fn count_words<'env, 'a>(
     vrt_entries: &'env MinEntries,
-    mmap: &'env Mmap
-) -> VocabBuilder {
-    crossbeam::scope(|scope| {
+    mmap: &'env Mmap,
+    attr: &'env AttrSelection,
+    elems: &'env VrtElems,
+    lenient: bool,
+    errors: &'env ErrorLog
+) -> Result<(VocabBuilder, u64), Error> {
+    let counts = crossbeam::scope(|scope| {
         let (snd, rcv) = unbounded();
-        buffered_extract(scope, vrt_entries, mmap, move |mut reader| {
+        buffered_extract(scope, vrt_entries, mmap, lenient, errors, move |_entry, reader| {
             let mut vocab = VocabBuilder::new();
-            let mut it = VrtFile::new(&mut reader, |vrt_text: VrtText| -> Option<()> {
+            let mut doc_count = 0;
+            let mut it = VrtFile::new(reader, attr, elems, |vrt_text: VrtText| -> Option<()> {
                 vrt_text.for_each(|tok| {
                     vocab.add(tok);
                 });
+                doc_count += 1;
                 Some(())
             });
             while it.next().is_some() {}
-            println!("vocab len: {}", vocab.acc.len());
-            snd.send(vocab).unwrap()
+            snd.send((vocab, doc_count)).is_ok()
         });
-        rcv.iter().reduce(|mut acc, other| {
+        parallel_reduce(rcv, |(mut acc, acc_docs), (other, other_docs)| {
             acc.merge(other);
-            acc
-        }).unwrap()
-    }).unwrap()
+            (acc, acc_docs + other_docs)
+        }).unwrap_or_else(|| (VocabBuilder::new(), 0))
+    }).unwrap();
+    errors.check()?;
+    Ok(counts)
 }
 
-pub fn make_doc_bows<'env, 'a>(
-    scope: &Scope<'env>,
-    vrt_entries: &'env MinEntries,
-    mmap: &'env Mmap,
-    vocab: &'env VocabMap
-) -> Receiver<DocBow>
-{
+/// Sends every document of `corpus` with its metadata
+pub fn make_doc_bows<'env>(scope: &Scope<'env>, corpus: &'env VrtCorpus, vocab: &'env VocabMap) -> Receiver<(DocBow, DocMeta)> {
+    let VrtCorpus { vrt_entries, mmap, attr, elems, lenient, errors } = corpus;
     let (snd, rcv) = bounded(1024);
-    buffered_extract(scope, vrt_entries, mmap, move |mut reader| {
-        for doc in VrtFile::new(&mut reader, |vrt_text: VrtText| {
-            let mut counts: BTreeMap<u32, u32> = BTreeMap::new();
+    buffered_extract(scope, vrt_entries, mmap, *lenient, errors, move |entry, reader| {
+        let proc_doc = |mut vrt_text: VrtText| {
+            let meta = std::mem::take(&mut vrt_text.meta);
+            let mut counts: BTreeMap<u32, u64> = BTreeMap::new();
             let mut doc_count = 0;
             vrt_text.for_each(|tok| {
                 let maybe_vocab_idx = vocab.get(tok);
@@ -200,46 +680,178 @@ pub fn make_doc_bows<'env, 'a>(
                     doc_count += 1
                 }
             });
-            Some((doc_count, counts))
-        }) {
-            snd.send(doc).unwrap();
+            Some(((doc_count, counts), meta))
+        };
+        let mut file_meta = DocMeta::new();
+        file_meta.insert("path".to_owned(), entry_path(entry).to_string_lossy().into_owned());
+        for doc in VrtFile::new(reader, attr, elems, proc_doc).with_meta(file_meta) {
+            if snd.send(doc).is_err() {
+                return false;
+            }
         }
+        true
     });
     rcv
 }
 
 pub struct VrtCorpus {
     vrt_entries: MinEntries,
-    mmap: Mmap
+    mmap: Mmap,
+    attr: AttrSelection,
+    elems: VrtElems,
+    lenient: bool,
+    errors: ErrorLog
 }
 
 impl VrtCorpus {
-    pub fn new(vrt_entries: MinEntries, mmap: Mmap) -> VrtCorpus {
+    pub fn new(vrt_entries: MinEntries, mmap: Mmap, attr: AttrSelection, elems: VrtElems, lenient: bool) -> VrtCorpus {
         VrtCorpus {
-            vrt_entries, mmap
+            vrt_entries, mmap, attr, elems, lenient, errors: ErrorLog::default()
         }
     }
 
-    pub fn new_from_path(path: &Path) -> VrtCorpus {
-        let (mmap, entries) = open_piz(path, is_vrt_file);
-        println!("{} entires in {}", entries.len(), path.to_str().unwrap());
+    /// Reports unreadable entries to `errors` rather than failing on the first one
+    pub fn with_error_log(mut self, errors: ErrorLog) -> VrtCorpus {
+        self.errors = errors;
+        self
+    }
+
+    pub fn new_from_path(path: &Path, token_key: TokenKey) -> Result<VrtCorpus, Error> {
+        VrtCorpus::new_with_options(path, token_key, &VrtOptions::default())
+    }
+
+    pub fn new_with_options(path: &Path, token_key: TokenKey, options: &VrtOptions) -> Result<VrtCorpus, Error> {
+        let (mmap, entries) = open_piz(path, is_vrt_file)?;
+        println!("{} entires in {}", entries.len(), path.display());
         if entries.len() == 0 {
-            panic!("No VRT files found in {}", path.to_str().unwrap());
+            return Err(Error::in_file(path, Error::Format("No VRT files found".to_owned())));
         }
-        VrtCorpus::new(
+        Ok(VrtCorpus::new(
             entries,
-            mmap
-        )
+            mmap,
+            options.attr.clone().unwrap_or_else(|| AttrSelection::from_token_key(token_key)),
+            VrtElems::new(&options.doc_unit, options.token_elem.as_deref()),
+            options.lenient
+        ))
     }
 }
 
 impl Corpus for VrtCorpus {
-    fn count_words(&self) -> (VocabBuilder, u32) {
-        let vocab = count_words(&self.vrt_entries, &self.mmap);
-        (vocab, self.vrt_entries.len() as u32)
+    fn count_words(&self) -> Result<(VocabBuilder, u64), Error> {
+        count_words(&self.vrt_entries, &self.mmap, &self.attr, &self.elems, self.lenient, &self.errors)
+    }
+
+    fn gen_doc_bows<'env>(&'env self, scope: &Scope<'env>, vocab: &'env VocabMap) -> Receiver<Result<DocBow, Error>> {
+        let rcv = make_doc_bows(scope, self, vocab);
+        self.errors.forward_checked_map(scope, rcv, |(doc, _meta)| doc)
+    }
+
+    fn gen_doc_bows_with_meta<'env>(&'env self, scope: &Scope<'env>, vocab: &'env VocabMap) -> Receiver<Result<(DocBow, DocMeta), Error>> {
+        let rcv = make_doc_bows(scope, self, vocab);
+        self.errors.forward_checked(scope, rcv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Read};
+    use crate::error::OnError;
+    use super::*;
+
+    /// Reads `vrt` with <text> documents, returning the word forms of each document and the
+    /// reasons of any errors reported
+    fn read_docs(vrt: &str, lenient: bool) -> (Vec<Vec<String>>, Vec<String>) {
+        let errors = ErrorLog::new(OnError::Skip);
+        let reader: EntryBufReader = BufReader::new(Box::new(vrt.as_bytes()) as Box<dyn Read + Send>);
+        let mut vrt_reader = VrtReader::new(reader, Path::new("test.vrt"), lenient, &errors);
+        let attr = AttrSelection::from_token_key(TokenKey::WordForm);
+        let elems = VrtElems::new(&VrtDocUnit::Text, None);
+        let docs = VrtFile::new(&mut vrt_reader, &attr, &elems, |vrt_text: VrtText| {
+            let mut toks = Vec::new();
+            vrt_text.for_each(|tok| toks.push(String::from_utf8_lossy(tok).into_owned()));
+            Some(toks)
+        }).collect();
+        let reasons = errors.skipped().into_iter().map(|skipped| skipped.reason).collect();
+        (docs, reasons)
+    }
+
+    fn words(docs: &[&[&str]]) -> Vec<Vec<String>> {
+        docs.iter().map(|doc| doc.iter().map(|tok| (*tok).to_owned()).collect()).collect()
+    }
+
+    /// Checks that strict mode reports `reason` at `line_no` and keeps the tokens before it,
+    /// while lenient mode reads `lenient_docs` without reporting anything
+    fn check_malformed(vrt: &str, line_no: u64, reason: &str, strict_docs: &[&[&str]], lenient_docs: &[&[&str]]) {
+        let (docs, reasons) = read_docs(vrt, false);
+        assert_eq!(docs, words(strict_docs));
+        assert_eq!(reasons.len(), 1, "{:?}", reasons);
+        let at_line = format!("malformed VRT at line {}: {}", line_no, reason);
+        assert!(reasons[0].starts_with(&at_line), "{} doesn't start with {}", reasons[0], at_line);
+        let (docs, reasons) = read_docs(vrt, true);
+        assert_eq!(docs, words(lenient_docs));
+        assert!(reasons.is_empty(), "{:?}", reasons);
+    }
+
+    #[test]
+    fn well_formed() {
+        let vrt = "<text id=\"1\">\n<sentence>\na\nb\n</sentence>\n</text>\n<text id=\"2\">\n<s>\nc\n</s>\n</text>\n";
+        for lenient in [false, true].iter() {
+            let (docs, reasons) = read_docs(vrt, *lenient);
+            assert_eq!(docs, words(&[&["a", "b"], &["c"]]));
+            assert!(reasons.is_empty());
+        }
+    }
+
+    #[test]
+    fn stray_end_tag() {
+        check_malformed(
+            "<text>\n<s>\na\n</s>\n</p>\n<s>\nb\n</s>\n</text>\n<text>\n<s>\nc\n</s>\n</text>\n",
+            5,
+            "end tag without a start tag",
+            &[&["a"]],
+            &[&["a", "b"], &["c"]]
+        );
+    }
+
+    #[test]
+    fn end_tag_closes_unclosed_elements() {
+        check_malformed(
+            "<text>\n<p>\n<s>\na\n</p>\n<s>\nb\n</s>\n</text>\n<text>\n<s>\nc\n</s>\n</text>\n",
+            5,
+            "end tag closes unclosed <s>",
+            &[&["a"]],
+            &[&["a", "b"], &["c"]]
+        );
+    }
+
+    #[test]
+    fn unterminated_tag() {
+        // In lenient mode the <s> is never opened so b is outside any sentence and its end tag
+        // is stray
+        check_malformed(
+            "<text>\n<s>\na\n</s>\n<s id=\"2\"\nb\n</s>\n</text>\n<text>\n<s>\nc\n</s>\n</text>\n",
+            5,
+            "unterminated tag",
+            &[&["a"]],
+            &[&["a"], &["c"]]
+        );
+    }
+
+    #[test]
+    fn bad_entity() {
+        check_malformed(
+            "<text>\n<s>\na\nb&bogus;\n&amp;\n</s>\n</text>\n",
+            4,
+            "bad entity in token line",
+            &[&["a"]],
+            &[&["a", "&"]]
+        );
     }
 
-    fn gen_doc_bows<'env>(&'env self, scope: &Scope<'env>, vocab: &'env VocabMap) -> Receiver<DocBow> {
-        make_doc_bows(scope, &self.vrt_entries, &self.mmap, vocab)
+    #[test]
+    fn tokens_without_documents() {
+        let (docs, reasons) = read_docs("<doc>\n<s>\na\n</s>\n</doc>\n", true);
+        assert!(docs.is_empty());
+        assert_eq!(reasons, vec!["token lines but no <text> document elements".to_owned()]);
     }
 }
@@ -1,25 +1,40 @@
 use std::path::Path;
-use std::io::{BufReader, Cursor, Read};
+use std::io::{self, BufReader, Cursor, Read};
 use std::fs::File;
 
 use memmap::Mmap;
 use itertools::Itertools;
 use piz::{CompressionMethod, ZipArchive};
 use piz::read::{read_direct, FileMetadata};
+use crate::error::{Error, Result};
 
 
-pub type MinEntry  = (usize, u32, usize, CompressionMethod, usize);
+pub type MinEntry  = (usize, u32, usize, CompressionMethod, usize, Box<Path>);
 pub type MinEntries = Vec<MinEntry>;
 pub type WholeEntryReader = Cursor<Box<[u8]>>;
 pub type EntryBufReader<'a> = BufReader<Box<dyn Read + Send + 'a>>;
 const READ_CHUNK_SIZE: usize = 64 * 1024;
-pub const UNZIP_READERS: usize = 4;
 
 
-pub(crate) fn mmap_file(path: &Path) -> Mmap {
+pub fn compressed_size(entry: &MinEntry) -> usize {
+    entry.4
+}
+
+pub fn entry_path(entry: &MinEntry) -> &Path {
+    &entry.5
+}
+
+
+/// Checks for the local file header signature at the start of a zip archive
+pub fn is_zip(buf: &[u8]) -> bool {
+    buf.starts_with(b"PK\x03\x04")
+}
+
+
+pub(crate) fn mmap_file(path: &Path) -> Result<Mmap> {
     // XXX: This should be unsafe if this is a library
-    let zip_file = File::open(path).unwrap();
-    unsafe { Mmap::map(&zip_file).unwrap() }
+    let zip_file = File::open(path).map_err(|err| Error::in_file(path, err.into()))?;
+    unsafe { Mmap::map(&zip_file) }.map_err(|err| Error::in_file(path, err.into()))
 }
 
 
@@ -32,6 +47,7 @@ pub fn filter_zip_entries(zip_reader: &ZipArchive, pred: fn(&FileMetadata) -> bo
                 entry.size,
                 entry.compression_method,
                 entry.compressed_size,
+                Box::from(entry.path.as_path()),
             ))
         } else {
             None
@@ -40,23 +56,32 @@ pub fn filter_zip_entries(zip_reader: &ZipArchive, pred: fn(&FileMetadata) -> bo
 }
 
 
-pub fn open_piz(path: &Path, pred: fn(&FileMetadata) -> bool) -> (Mmap, MinEntries) {
-    let mmap = mmap_file(path);
-    let zip_reader: ZipArchive = ZipArchive::new(&mmap).unwrap();
-    let entries = filter_zip_entries(&zip_reader, pred);
-    (mmap, entries)
+pub fn open_piz(path: &Path, pred: fn(&FileMetadata) -> bool) -> Result<(Mmap, MinEntries)> {
+    let mmap = mmap_file(path)?;
+    let entries = {
+        let zip_reader: ZipArchive = ZipArchive::new(&mmap).map_err(|err| Error::in_file(path, err.into()))?;
+        filter_zip_entries(&zip_reader, pred)
+    };
+    Ok((mmap, entries))
+}
+
+
+fn open_entry<'a>(mmap: &'a [u8], entry: &MinEntry) -> io::Result<Box<dyn Read + Send + 'a>> {
+    let (header_offset, crc32, _size, compression_method, compressed_size, _) = entry;
+    read_direct(mmap, *header_offset, *crc32, *compression_method, *compressed_size)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
 }
 
 
-pub fn read_whole_file(mmap: &Mmap, entry: &MinEntry) -> WholeEntryReader {
-    let (header_offset, crc32, size, compression_method, compressed_size) = entry;
-    let mut contents = Vec::with_capacity(*size);
-    read_direct(&mmap, *header_offset, *crc32, *compression_method, *compressed_size).unwrap().read_to_end(&mut contents).unwrap();
-    Cursor::new(contents.into_boxed_slice())
+/// Decompresses a whole entry, checking its CRC
+pub fn read_whole_file(mmap: &Mmap, entry: &MinEntry) -> io::Result<WholeEntryReader> {
+    let mut contents = Vec::with_capacity(entry.2);
+    open_entry(mmap, entry)?.read_to_end(&mut contents)?;
+    Ok(Cursor::new(contents.into_boxed_slice()))
 }
 
 
-pub fn read_buf<'a>(mmap: &'a Mmap, entry: &MinEntry) -> EntryBufReader<'a> {
-    let (header_offset, crc32, size, compression_method, compressed_size) = entry;
-    BufReader::with_capacity(READ_CHUNK_SIZE, read_direct(&mmap, *header_offset, *crc32, *compression_method, *compressed_size).unwrap())
+/// Streams an entry. A bad CRC only shows up as an error once the end of the entry is read.
+pub fn read_buf<'a>(mmap: &'a [u8], entry: &MinEntry) -> io::Result<EntryBufReader<'a>> {
+    Ok(BufReader::with_capacity(READ_CHUNK_SIZE, open_entry(mmap, entry)?))
 }